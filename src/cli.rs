@@ -0,0 +1,728 @@
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgGroup, SubCommand};
+
+/// examples section of subcommand help, one invocation per argument
+macro_rules! examples {
+    ($($line:expr),+ $(,)?) => {
+        concat!("EXAMPLES:", $("\n    rusty-configs ", $line),+)
+    };
+}
+
+/// builds command line interface definition (shared by argument parsing and completions)
+pub fn build_cli() -> App<'static, 'static> {
+    App::new("rusty-configs")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Synchronizes configs across devices with sqlite")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .global_setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("database")
+                .long("db")
+                .short("d")
+                .takes_value(true)
+                .value_name("FILE")
+                .global(true)
+                .help("Sqlite database file location (default db.sqlite)"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .global(true)
+                .help("Only report files and db rows that would change, without changing them"),
+        )
+        .arg(
+            Arg::with_name("key-file")
+                .long("key-file")
+                .short("k")
+                .takes_value(true)
+                .value_name("FILE")
+                .global(true)
+                .help("File encrypted configs are encrypted by (instead of passphrase)"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Creates database file and its tables")
+                .after_help(examples!("init", "init -d ~/sync/configs.sqlite")),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Applies pending database schema migrations")
+                .after_help(examples!("migrate --dry-run", "migrate")),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Adds config, version or variable to database")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .alias("configs")
+                        .about("Stores file (or every file of directory) as config of version")
+                        .arg(path_arg().required(true))
+                        .arg(version_arg().required(true))
+                        .arg(
+                            Arg::with_name("recursive")
+                                .long("recursive")
+                                .short("R")
+                                .help("Add directory recursively and track it for new files"),
+                        )
+                        .arg(
+                            Arg::with_name("follow")
+                                .long("follow")
+                                .short("L")
+                                .conflicts_with("recursive")
+                                .help("Store contents of file symlink points to instead of the link"),
+                        )
+                        .arg(
+                            Arg::with_name("template")
+                                .long("template")
+                                .short("t")
+                                .conflicts_with("recursive")
+                                .help("Render {{ name }} placeholders with variables of version on write"),
+                        )
+                        .arg(
+                            Arg::with_name("encrypt")
+                                .long("encrypt")
+                                .short("e")
+                                .conflicts_with("recursive")
+                                .help("Store file encrypted by passphrase (or --key-file)"),
+                        )
+                        .arg(
+                            Arg::with_name("include")
+                                .long("include")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .value_name("GLOB")
+                                .requires("recursive")
+                                .help("Only add files matching glob (relative to directory)"),
+                        )
+                        .arg(
+                            Arg::with_name("exclude")
+                                .long("exclude")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .value_name("GLOB")
+                                .requires("recursive")
+                                .help("Skip files matching glob (relative to directory)"),
+                        )
+                        .after_help(examples!(
+                            "add config -p ~/.bashrc -v home",
+                            "add config -p ~/.vimrc -v home --follow",
+                            "add config -p ~/.config/nvim -v home --recursive",
+                            "add config -p ~/.config/nvim -v home -R --include \"*.lua\" --exclude \"plugin/**\"",
+                            "add config -p ~/.gitconfig -v home --template",
+                            "add config -p ~/.netrc -v home --encrypt",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("version")
+                        .alias("versions")
+                        .about("Creates new version (set of configs of one system)")
+                        .arg(version_arg().required(true).help("Name of new version"))
+                        .arg(parent_arg())
+                        .after_help(examples!("add version -v home", "add version -v work --parent base")),
+                )
+                .subcommand(
+                    SubCommand::with_name("variable")
+                        .alias("variables")
+                        .about("Sets variable templates of version are rendered with")
+                        .arg(version_arg().required(true))
+                        .arg(assignment_arg())
+                        .after_help(examples!("add variable -v work email=me@work.com")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists versions, configs or variables stored in database")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("version")
+                        .alias("versions")
+                        .about("Lists versions, or configs of one version when name is given")
+                        .arg(Arg::with_name("name").index(1).help("Name of version"))
+                        .arg(format_arg())
+                        .after_help(examples!(
+                            "list versions",
+                            "list version home",
+                            "list versions --format json",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .alias("configs")
+                        .about("Lists configs grouped by version")
+                        .arg(format_arg())
+                        .after_help(examples!("list configs", "list configs --format tsv")),
+                )
+                .subcommand(
+                    SubCommand::with_name("variable")
+                        .alias("variables")
+                        .about("Lists variables of version (including inherited ones)")
+                        .arg(version_arg().required(true))
+                        .arg(format_arg())
+                        .after_help(examples!("list variables -v work")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Removes config, variable or version (with all its configs) from database")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .alias("configs")
+                        .about("Removes configs matching id, path or name")
+                        .arg(id_arg())
+                        .arg(path_arg())
+                        .arg(name_arg())
+                        .group(
+                            ArgGroup::with_name("target")
+                                .args(&["id", "path", "name"])
+                                .required(true),
+                        )
+                        .after_help(examples!(
+                            "delete config -i 3",
+                            "delete config -p ~/.bashrc",
+                            "delete config -n .bashrc",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("version")
+                        .alias("versions")
+                        .about("Removes version and all its configs")
+                        .arg(version_arg().required(true))
+                        .after_help(examples!("delete version -v home")),
+                )
+                .subcommand(
+                    SubCommand::with_name("variable")
+                        .alias("variables")
+                        .about("Removes variable of version")
+                        .arg(version_arg().required(true))
+                        .arg(
+                            Arg::with_name("name")
+                                .index(1)
+                                .required(true)
+                                .value_name("NAME")
+                                .help("Name of variable"),
+                        )
+                        .after_help(examples!("delete variable -v work email")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("Changes attributes of config, version or variable")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .alias("configs")
+                        .about("Changes path or version of config matched by path and version")
+                        .arg(path_arg().required(true).help("Current path of config"))
+                        .arg(version_arg().required(true))
+                        .arg(
+                            Arg::with_name("value")
+                                .index(1)
+                                .required(true)
+                                .value_name("COLUMN=VALUE")
+                                .help(
+                            "New value, path=<new path>, version=<version name>, kind=<file|symlink> or template=<true|false> or encrypted=<true|false>",
+                        ),
+                        )
+                        .after_help(examples!(
+                            "update config -p /tmp/old -v home path=/tmp/new",
+                            "update config -p ~/.bashrc -v home version=work",
+                            "update config -p ~/.vimrc -v home kind=file",
+                            "update config -p ~/.gitconfig -v home template=true",
+                            "update config -p ~/.netrc -v home encrypted=true",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("version")
+                        .alias("versions")
+                        .about("Renames version or changes its parent")
+                        .arg(version_arg().required(true).help("Current name of version"))
+                        .arg(
+                            Arg::with_name("value")
+                                .index(1)
+                                .value_name("NEW_NAME")
+                                .help("New name of version"),
+                        )
+                        .arg(parent_arg())
+                        .arg(
+                            Arg::with_name("no-parent")
+                                .long("no-parent")
+                                .help("Stop inheriting configs of parent version"),
+                        )
+                        .group(
+                            ArgGroup::with_name("change")
+                                .args(&["value", "parent", "no-parent"])
+                                .multiple(true)
+                                .required(true),
+                        )
+                        .after_help(examples!(
+                            "update version -v home laptop",
+                            "update version -v home --parent base",
+                            "update version -v home --no-parent",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("variable")
+                        .alias("variables")
+                        .about("Changes value of variable of version")
+                        .arg(version_arg().required(true))
+                        .arg(assignment_arg())
+                        .after_help(examples!("update variable -v work email=me@work.org")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bind")
+                .about("Binds machine to its default version (used when -v is not given)")
+                .arg(version_arg().help("Default version of machine"))
+                .arg(
+                    Arg::with_name("unset")
+                        .long("unset")
+                        .help("Remove binding of machine"),
+                )
+                .arg(
+                    Arg::with_name("hostname")
+                        .long("hostname")
+                        .takes_value(true)
+                        .value_name("HOSTNAME")
+                        .help("Machine to bind (default this one)"),
+                )
+                .group(
+                    ArgGroup::with_name("binding")
+                        .args(&["config-version", "unset"])
+                        .required(true),
+                )
+                .after_help(examples!(
+                    "bind -v work",
+                    "bind -v home --hostname home-desktop",
+                    "bind --unset",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("whoami")
+                .about("Shows hostname of this machine and its default version")
+                .after_help(examples!("whoami")),
+        )
+        .subcommand(
+            SubCommand::with_name("rewrite-paths")
+                .about("Rewrites absolute paths under home into portable form (~/..., $XDG_CONFIG_HOME/...)")
+                .after_help(examples!("rewrite-paths --dry-run", "rewrite-paths")),
+        )
+        .subcommand(
+            SubCommand::with_name("read")
+                .about("Reads files of configs into database (real files => db)")
+                .args(&selector_args())
+                .after_help(examples!(
+                    "read",
+                    "read -v home",
+                    "read -g \"~/.config/nvim/**\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("write")
+                .about("Writes configs from database to their files (db => real files)")
+                .arg(
+                    Arg::with_name("revision")
+                        .long("revision")
+                        .short("r")
+                        .takes_value(true)
+                        .value_name("ID")
+                        .conflicts_with_all(&["at", "path", "name", "config-version", "glob"])
+                        .help("Write only one config revision (see history command)"),
+                )
+                .args(&selector_args())
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .takes_value(true)
+                        .value_name("DATE")
+                        .help(
+                            "Write configs as they were stored at date (YYYY-MM-DD [HH:MM[:SS]])",
+                        ),
+                )
+                .arg(no_owner_arg())
+                .after_help(examples!(
+                    "write",
+                    "write --no-owner",
+                    "write -v home -n .bashrc",
+                    "write --all-versions",
+                    "write --revision 3",
+                    "write --at \"2021-01-05 18:00\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("undo")
+                .about("Restores files overwritten by the last write (or sync) on this machine")
+                .after_help(examples!("undo")),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes configs of version into directory tree or tarball with manifest")
+                .arg(version_arg().required(true))
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("DIR|FILE.tar.gz")
+                        .help("New directory or tarball (by .tar.gz / .tgz extension)"),
+                )
+                .arg(
+                    Arg::with_name("decrypt-secrets")
+                        .long("decrypt-secrets")
+                        .help("Export encrypted configs decrypted (skipped otherwise), only owner can read the result"),
+                )
+                .after_help(examples!(
+                    "export -v home --to ~/home-configs",
+                    "export -v home --to home-configs.tar.gz",
+                    "export -v home --to home-configs.tar.gz --decrypt-secrets",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Adds configs from directory tree, tarball, dotfiles repository or stow packages")
+                .arg(version_arg().help(
+                    "Version to import into, created when missing (default version of manifest)",
+                ))
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("DIR|FILE.tar.gz")
+                        .help("Directory or tarball, with manifest.json of export its paths are used"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Directory files of tree without manifest belong to (default ~)"),
+                )
+                .arg(
+                    Arg::with_name("stow")
+                        .long("stow")
+                        .help("Tree is GNU Stow layout (top directories are packages, dot- means .)"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("GLOB")
+                        .help("Skip files matching glob (relative to tree)"),
+                )
+                .after_help(examples!(
+                    "import --from home-configs.tar.gz",
+                    "import -v home --from ~/dotfiles --exclude README.md",
+                    "import -v home --from ~/dotfiles --stow",
+                    "import -v server --from ./etc --target /etc",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Lists stored revisions of config")
+                .arg(path_arg().required(true))
+                .arg(format_arg())
+                .after_help(examples!("history -p ~/.bashrc", "history -p ~/.bashrc --format json")),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Shows differences between files on disk and database, exits with 1 if any")
+                .args(&selector_args())
+                .after_help(examples!("diff", "diff -v home", "diff -p ~/.bashrc")),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Summarizes drift of tracked configs between disk and database")
+                .args(&selector_args())
+                .arg(
+                    Arg::with_name("porcelain")
+                        .long("porcelain")
+                        .conflicts_with("format")
+                        .help("Machine readable output (tab separated code, version, path)"),
+                )
+                .arg(format_arg())
+                .after_help(examples!(
+                    "status",
+                    "status -v home --porcelain",
+                    "status --format json",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("sync")
+                .about("Syncs files and database both ways, merging configs changed on both sides")
+                .args(&selector_args())
+                .arg(
+                    Arg::with_name("markers")
+                        .long("markers")
+                        .help("Write conflict markers into files that could not be merged"),
+                )
+                .arg(no_owner_arg())
+                .after_help(examples!("sync", "sync -v home --markers")),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints shell completion script to stdout")
+                .arg(
+                    Arg::with_name("shell")
+                        .index(1)
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"])
+                        .help("Shell to generate completions for"),
+                )
+                .after_help(examples!(
+                    "completions bash > /etc/bash_completion.d/rusty-configs",
+                    "completions zsh > ~/.zfunc/_rusty-configs",
+                    "completions fish > ~/.config/fish/completions/rusty-configs.fish",
+                )),
+        )
+}
+
+/// path of config file
+fn path_arg() -> Arg<'static, 'static> {
+    Arg::with_name("path")
+        .long("path")
+        .short("p")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Path of target config")
+}
+
+/// name of config file (last token of path)
+fn name_arg() -> Arg<'static, 'static> {
+    Arg::with_name("name")
+        .long("name")
+        .short("n")
+        .takes_value(true)
+        .value_name("NAME")
+        .help("Name of target config (for path /tmp/test it is -n test)")
+}
+
+/// id of config
+fn id_arg() -> Arg<'static, 'static> {
+    Arg::with_name("id")
+        .long("id")
+        .short("i")
+        .takes_value(true)
+        .value_name("ID")
+        .help("Id of target config")
+}
+
+/// arguments narrowing configs command works on (see Selector)
+fn selector_args() -> [Arg<'static, 'static>; 5] {
+    [
+        path_arg(),
+        name_arg(),
+        version_arg().help("Version of configs (default version of machine, see whoami)"),
+        Arg::with_name("glob")
+            .long("glob")
+            .short("g")
+            .takes_value(true)
+            .value_name("GLOB")
+            .help("Glob matched against paths of configs"),
+        Arg::with_name("all-versions")
+            .long("all-versions")
+            .short("a")
+            .conflicts_with("config-version")
+            .help("Configs of all versions, ignoring default version of machine"),
+    ]
+}
+
+/// NAME=VALUE of template variable
+fn assignment_arg() -> Arg<'static, 'static> {
+    Arg::with_name("value")
+        .index(1)
+        .required(true)
+        .value_name("NAME=VALUE")
+        .help("Variable and its value")
+}
+
+/// output format of listing and status commands
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .short("f")
+        .takes_value(true)
+        .possible_values(&["json", "tsv", "plain"])
+        .value_name("FORMAT")
+        .help("Machine readable output with stable field names instead of tree")
+}
+
+/// version layered below another one
+fn parent_arg() -> Arg<'static, 'static> {
+    Arg::with_name("parent")
+        .long("parent")
+        .takes_value(true)
+        .value_name("VERSION")
+        .help("Parent version, its configs are inherited unless overridden by path")
+}
+
+/// skips restoring recorded owner and group of written files
+fn no_owner_arg() -> Arg<'static, 'static> {
+    Arg::with_name("no-owner")
+        .long("no-owner")
+        .help("Do not restore owner and group of files (only restored when running as root)")
+}
+
+/// name of version
+fn version_arg() -> Arg<'static, 'static> {
+    Arg::with_name("config-version")
+        .long("config-version")
+        .short("v")
+        .takes_value(true)
+        .value_name("VERSION")
+        .help("Version of config (name of the system where it is)")
+}
+
+/// testing that every subcommand parses its typical invocation
+#[test]
+fn subcommand_invocations() {
+    let invocations: &[(&str, &[&str])] = &[
+        ("init", &["init", "-d", "configs.sqlite"]),
+        ("migrate", &["migrate", "--dry-run"]),
+        (
+            "add config",
+            &[
+                "add",
+                "config",
+                "-p",
+                "~/.bashrc",
+                "-v",
+                "home",
+                "--template",
+            ],
+        ),
+        (
+            "add config",
+            &[
+                "add",
+                "config",
+                "-p",
+                "~/.config/nvim",
+                "-v",
+                "home",
+                "-R",
+                "--include",
+                "*.lua",
+            ],
+        ),
+        (
+            "add version",
+            &["add", "version", "-v", "work", "--parent", "base"],
+        ),
+        (
+            "add variable",
+            &["add", "variable", "-v", "work", "email=me@work.com"],
+        ),
+        ("list version", &["list", "versions", "--format", "json"]),
+        ("list config", &["list", "configs", "--format", "tsv"]),
+        ("list variable", &["list", "variables", "-v", "work"]),
+        ("delete config", &["delete", "config", "-i", "3"]),
+        ("delete version", &["delete", "version", "-v", "home"]),
+        (
+            "delete variable",
+            &["delete", "variable", "-v", "work", "email"],
+        ),
+        (
+            "update config",
+            &[
+                "update",
+                "config",
+                "-p",
+                "~/.vimrc",
+                "-v",
+                "home",
+                "kind=file",
+            ],
+        ),
+        (
+            "update version",
+            &["update", "version", "-v", "home", "--no-parent"],
+        ),
+        (
+            "update variable",
+            &["update", "variable", "-v", "work", "email=me@work.org"],
+        ),
+        (
+            "bind",
+            &["bind", "-v", "home", "--hostname", "home-desktop"],
+        ),
+        ("whoami", &["whoami"]),
+        ("rewrite-paths", &["rewrite-paths", "--dry-run"]),
+        ("read", &["read", "-v", "home", "-g", "~/.config/nvim/**"]),
+        (
+            "write",
+            &["write", "--at", "2021-01-05 18:00", "--no-owner"],
+        ),
+        ("undo", &["undo", "-d", "configs.sqlite"]),
+        (
+            "export",
+            &[
+                "export",
+                "-v",
+                "home",
+                "--to",
+                "home.tar.gz",
+                "--decrypt-secrets",
+            ],
+        ),
+        (
+            "import",
+            &[
+                "import",
+                "-v",
+                "home",
+                "--from",
+                "dotfiles",
+                "--stow",
+                "--exclude",
+                "README.md",
+            ],
+        ),
+        (
+            "history",
+            &["history", "-p", "~/.bashrc", "--format", "json"],
+        ),
+        ("diff", &["diff", "-p", "~/.bashrc"]),
+        ("status", &["status", "-v", "home", "--porcelain"]),
+        ("sync", &["sync", "-v", "home", "--markers"]),
+        ("completions", &["completions", "fish"]),
+    ];
+    for (subcommand, args) in invocations {
+        let matches = build_cli()
+            .get_matches_from_safe(std::iter::once(&"rusty-configs").chain(args.iter()))
+            .unwrap_or_else(|e| panic!("{:?} rejected: {}", args, e.message));
+        let mut names: Vec<&str> = vec![];
+        let mut current = &matches;
+        while let (name, Some(sub_matches)) = current.subcommand() {
+            names.push(name);
+            current = sub_matches;
+        }
+        assert_eq!(*subcommand, names.join(" "));
+    }
+
+    // missing required values are usage errors
+    for args in [
+        &["add", "config", "-v", "home"][..],
+        &["completions", "tcsh"],
+        &[],
+    ] {
+        let result =
+            build_cli().get_matches_from_safe(std::iter::once(&"rusty-configs").chain(args.iter()));
+        assert!(result.is_err(), "{:?} accepted", args);
+    }
+}
+
+/// testing that definition is consistent (clap checks it while generating completions)
+#[test]
+fn valid_definition() {
+    for shell in [clap::Shell::Bash, clap::Shell::Zsh, clap::Shell::Fish] {
+        let mut script: Vec<u8> = vec![];
+        build_cli().gen_completions_to("rusty-configs", shell, &mut script);
+        assert!(!script.is_empty());
+    }
+}
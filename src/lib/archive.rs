@@ -0,0 +1,507 @@
+use chrono::Local;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use walkdir::WalkDir;
+
+use super::entities::ConfigKind;
+use super::{Error, Result};
+
+/// name of manifest file in root of exported tree
+pub static MANIFEST: &str = "manifest.json";
+
+/// directory of exported tree holding templates (with placeholders) of rendered configs
+static TEMPLATES: &str = ".templates";
+
+/// description of exported tree, written next to the configs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// name of exported version
+    pub version: String,
+    pub configs: Vec<ManifestEntry>,
+    /// variables templates were rendered with (recreated on import)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// config in exported tree
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// path of config as stored in db (may need expansion)
+    pub path: String,
+    /// location of config in tree (relative to its root)
+    pub file: String,
+    pub kind: ConfigKind,
+    /// permission bits of file
+    pub mode: Option<u32>,
+    /// modification time of file (unix timestamp)
+    pub mtime: Option<i64>,
+    /// config is template, file holds it rendered and source holds the template itself
+    #[serde(default)]
+    pub template: bool,
+    /// config is stored encrypted (file holds decrypted contents)
+    #[serde(default)]
+    pub encrypted: bool,
+    /// location of template in tree (templates only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// contents of exported config
+#[derive(Debug)]
+pub struct ExportedFile {
+    /// contents of file (rendered and decrypted) or target of symlink
+    pub data: Vec<u8>,
+    /// template with placeholders (templates only)
+    pub source: Option<Vec<u8>>,
+}
+
+/// entries of imported tree that are never configs (version control and stow metadata)
+static IGNORED: &[&str] = &[".git", ".gitmodules", ".stow-local-ignore"];
+
+/// file or symlink found in imported tree
+#[derive(Debug)]
+pub struct TreeFile {
+    /// location in tree (relative to its root)
+    pub file: String,
+    pub kind: ConfigKind,
+    /// contents of file or target of symlink
+    pub data: Vec<u8>,
+    pub mode: Option<u32>,
+    pub mtime: Option<i64>,
+    /// stored as template (from manifest)
+    pub template: bool,
+    /// stored encrypted (from manifest)
+    pub encrypted: bool,
+}
+
+/// location of stored path inside exported tree
+/// (~/x goes to home/x, $VAR/x to VAR/x and absolute /x to root/x)
+pub fn tree_path(path: &str) -> Result<String> {
+    let (top, rest) = if let Some(rest) = path.strip_prefix('~') {
+        ("home", rest)
+    } else if let Some(variable) = path.strip_prefix('$') {
+        let variable = variable.strip_prefix('{').unwrap_or(variable);
+        let end = variable
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(variable.len());
+        let rest = &variable[end..];
+        (&variable[..end], rest.strip_prefix('}').unwrap_or(rest))
+    } else {
+        ("root", path)
+    };
+    let mut components: Vec<&str> = vec![top];
+    for component in rest.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => {
+                return Err(Error::Usage(format!(
+                    "path {} leaves its directory, it can not be exported",
+                    path
+                )))
+            }
+            component => components.push(component),
+        }
+    }
+    if top.is_empty() || components.len() < 2 {
+        return Err(Error::Usage(format!("path {} can not be exported", path)));
+    }
+    Ok(components.join("/"))
+}
+
+/// true when export target is a tarball (by its extension), otherwise it is a directory
+pub fn is_tarball(target: &str) -> bool {
+    target.ends_with(".tar.gz") || target.ends_with(".tgz")
+}
+
+/// location of template of config in exported tree
+pub fn template_path(file: &str) -> String {
+    format!("{}/{}", TEMPLATES, file)
+}
+
+/// writes configs (files in order of manifest entries) with manifest
+/// into new directory or tarball, private one is readable only by owner (holds secrets)
+pub fn export(
+    target: &str,
+    manifest: &Manifest,
+    files: &[ExportedFile],
+    private: bool,
+) -> Result<()> {
+    let manifest_data = serde_json::to_vec_pretty(manifest)
+        .map_err(|e| Error::Decode(format!("manifest: {}", e)))?;
+    match is_tarball(target) {
+        true => export_tarball(target, manifest, files, &manifest_data, private),
+        false => export_directory(target, manifest, files, &manifest_data, private),
+    }
+}
+
+/// writes tree into directory (which has to be empty or missing)
+fn export_directory(
+    target: &str,
+    manifest: &Manifest,
+    files: &[ExportedFile],
+    manifest_data: &[u8],
+    private: bool,
+) -> Result<()> {
+    let root = Path::new(target);
+    let not_empty = fs::read_dir(root).is_ok_and(|mut entries| entries.next().is_some());
+    if not_empty {
+        return Err(Error::Conflict(format!(
+            "directory {} is not empty, export into new one",
+            target
+        )));
+    }
+    if private {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(root)
+            .and_then(|_| fs::set_permissions(root, fs::Permissions::from_mode(0o700)))
+            .map_err(|e| Error::io(target, e))?;
+    }
+    let write = |file: &str, contents: &[u8], kind: ConfigKind, mode: Option<u32>| {
+        let location = root.join(file);
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match kind {
+            ConfigKind::Symlink => {
+                std::os::unix::fs::symlink(String::from_utf8_lossy(contents).as_ref(), &location)
+            }
+            ConfigKind::File => {
+                fs::write(&location, contents)?;
+                match mode {
+                    Some(mode) => fs::set_permissions(&location, fs::Permissions::from_mode(mode)),
+                    None => Ok(()),
+                }
+            }
+        }
+    };
+    for (entry, exported) in manifest.configs.iter().zip(files) {
+        write(&entry.file, &exported.data, entry.kind, entry.mode)
+            .map_err(|e| Error::io(&entry.file, e))?;
+        if let (Some(source), Some(data)) = (&entry.source, &exported.source) {
+            write(source, data, ConfigKind::File, entry.mode).map_err(|e| Error::io(source, e))?;
+        }
+    }
+    write(MANIFEST, manifest_data, ConfigKind::File, None).map_err(|e| Error::io(target, e))
+}
+
+/// writes tree into new gzip compressed tarball
+fn export_tarball(
+    target: &str,
+    manifest: &Manifest,
+    files: &[ExportedFile],
+    manifest_data: &[u8],
+    private: bool,
+) -> Result<()> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(if private { 0o600 } else { 0o666 })
+        .open(target)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                Error::Conflict(format!("{} already exists, export into new file", target))
+            }
+            _ => Error::io(target, e),
+        })?;
+    let write = || -> io::Result<()> {
+        let mut tarball = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        let mut append = |file: &str, contents: &[u8], kind: ConfigKind, entry: &ManifestEntry| {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(entry.mode.unwrap_or(0o644) & 0o7777);
+            header.set_mtime(entry.mtime.unwrap_or(0).max(0) as u64);
+            match kind {
+                ConfigKind::File => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(contents.len() as u64);
+                    tarball.append_data(&mut header, file, contents)
+                }
+                ConfigKind::Symlink => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    let link = String::from_utf8_lossy(contents).into_owned();
+                    tarball.append_link(&mut header, file, link)
+                }
+            }
+        };
+        for (entry, exported) in manifest.configs.iter().zip(files) {
+            append(&entry.file, &exported.data, entry.kind, entry)?;
+            if let (Some(source), Some(data)) = (&entry.source, &exported.source) {
+                append(source, data, ConfigKind::File, entry)?;
+            }
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_mtime(Local::now().timestamp().max(0) as u64);
+        header.set_size(manifest_data.len() as u64);
+        tarball.append_data(&mut header, MANIFEST, manifest_data)?;
+        tarball.into_inner()?.finish()?.flush()
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(target);
+        Error::io(target, e)
+    })
+}
+
+/// reads files and symlinks of directory or tarball (by its extension)
+/// with their metadata, version control directories are skipped
+pub fn read_tree(source: &str) -> Result<Vec<TreeFile>> {
+    let mut files = match is_tarball(source) {
+        true => read_tarball(source)?,
+        false => read_directory(source)?,
+    };
+    files.retain(|tree_file| {
+        !tree_file
+            .file
+            .split('/')
+            .any(|component| IGNORED.contains(&component))
+    });
+    files.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(files)
+}
+
+/// takes manifest out of files read from tree (None when tree has none)
+pub fn take_manifest(files: &mut Vec<TreeFile>) -> Result<Option<Manifest>> {
+    let index = match files
+        .iter()
+        .position(|tree_file| tree_file.file == MANIFEST)
+    {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    let manifest = files.remove(index);
+    serde_json::from_slice(&manifest.data)
+        .map(Some)
+        .map_err(|e| Error::Decode(format!("{}: {}", MANIFEST, e)))
+}
+
+/// location of file of GNU Stow package relative to target directory,
+/// first directory is the package and dot- prefixes become dots
+/// (None for files outside of packages)
+pub fn stow_path(file: &str) -> Option<String> {
+    let (package, rest) = file.split_once('/')?;
+    if package.starts_with('.') {
+        return None;
+    }
+    let components: Vec<String> = rest
+        .split('/')
+        .map(|component| match component.strip_prefix("dot-") {
+            Some(name) => format!(".{}", name),
+            None => component.to_string(),
+        })
+        .collect();
+    Some(components.join("/"))
+}
+
+/// reads files of directory tree
+fn read_directory(source: &str) -> Result<Vec<TreeFile>> {
+    if !Path::new(source).is_dir() {
+        return Err(Error::NotFound(format!("directory {}", source)));
+    }
+    let mut files: Vec<TreeFile> = vec![];
+    let walker = WalkDir::new(source)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| !IGNORED.contains(&entry.file_name().to_string_lossy().as_ref()));
+    for entry in walker {
+        let entry = entry.map_err(|e| Error::io(source, e.into()))?;
+        let location = entry.path().to_string_lossy().into_owned();
+        let file = entry
+            .path()
+            .strip_prefix(source)
+            .map_or(location.clone(), |relative| {
+                relative.to_string_lossy().into_owned()
+            });
+        let metadata = entry
+            .metadata()
+            .map_err(|e| Error::io(&location, e.into()))?;
+        let (kind, data) = if entry.path_is_symlink() {
+            let target = fs::read_link(entry.path()).map_err(|e| Error::io(&location, e))?;
+            (ConfigKind::Symlink, target.as_os_str().as_bytes().to_vec())
+        } else if metadata.is_file() {
+            let data = fs::read(entry.path()).map_err(|e| Error::io(&location, e))?;
+            (ConfigKind::File, data)
+        } else {
+            continue;
+        };
+        files.push(TreeFile {
+            file,
+            kind,
+            data,
+            mode: Some(metadata.mode() & 0o7777).filter(|_| kind == ConfigKind::File),
+            mtime: Some(metadata.mtime()).filter(|_| kind == ConfigKind::File),
+            template: false,
+            encrypted: false,
+        });
+    }
+    Ok(files)
+}
+
+/// reads files of gzip compressed tarball
+fn read_tarball(source: &str) -> Result<Vec<TreeFile>> {
+    let file = fs::File::open(source).map_err(|e| Error::io(source, e))?;
+    let mut tarball = tar::Archive::new(GzDecoder::new(file));
+    let mut files: Vec<TreeFile> = vec![];
+    let mut read = || -> io::Result<()> {
+        for entry in tarball.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let file = path
+                .trim_start_matches("./")
+                .trim_end_matches('/')
+                .to_string();
+            let header = entry.header();
+            let (mode, mtime) = (header.mode().ok(), header.mtime().ok());
+            let (kind, data) = match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let mut data = vec![];
+                    entry.read_to_end(&mut data)?;
+                    (ConfigKind::File, data)
+                }
+                tar::EntryType::Symlink => match entry.link_name()? {
+                    Some(target) => (ConfigKind::Symlink, target.as_os_str().as_bytes().to_vec()),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            files.push(TreeFile {
+                file,
+                kind,
+                data,
+                mode: mode.filter(|_| kind == ConfigKind::File),
+                mtime: mtime
+                    .map(|mtime| mtime as i64)
+                    .filter(|_| kind == ConfigKind::File),
+                template: false,
+                encrypted: false,
+            });
+        }
+        Ok(())
+    };
+    read().map_err(|e| Error::io(source, e))?;
+    Ok(files)
+}
+
+/// testing placement of configs in exported tree
+#[test]
+fn tree_paths() -> Result<()> {
+    assert_eq!("home/.bashrc", tree_path("~/.bashrc")?);
+    assert_eq!(
+        "XDG_CONFIG_HOME/nvim/init.lua",
+        tree_path("$XDG_CONFIG_HOME/nvim/init.lua")?
+    );
+    assert_eq!("DOTFILES/vimrc", tree_path("${DOTFILES}/vimrc")?);
+    assert_eq!("root/etc/hosts", tree_path("/etc/hosts")?);
+    assert_eq!("root/etc/hosts", tree_path("/etc/./hosts")?);
+    assert!(tree_path("~/../etc/passwd").is_err());
+    assert!(tree_path("~").is_err());
+    Ok(())
+}
+
+/// testing that exported tarball is read back with its manifest
+#[test]
+fn tarball_round_trip() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("rusty-configs-tar-{}", std::process::id()));
+    fs::create_dir_all(&root).expect("create temp dir");
+    let tarball = root.join("home.tar.gz").to_string_lossy().into_owned();
+    let manifest = Manifest {
+        version: "home".to_string(),
+        variables: vec![("email".to_string(), "me@home".to_string())]
+            .into_iter()
+            .collect(),
+        configs: vec![
+            ManifestEntry {
+                path: "~/.bashrc".to_string(),
+                file: "home/.bashrc".to_string(),
+                kind: ConfigKind::File,
+                mode: Some(0o600),
+                mtime: Some(1_600_000_000),
+                template: true,
+                encrypted: true,
+                source: Some(template_path("home/.bashrc")),
+            },
+            ManifestEntry {
+                path: "~/.vimrc".to_string(),
+                file: "home/.vimrc".to_string(),
+                kind: ConfigKind::Symlink,
+                mode: None,
+                mtime: None,
+                template: false,
+                encrypted: false,
+                source: None,
+            },
+        ],
+    };
+    export(
+        &tarball,
+        &manifest,
+        &[
+            ExportedFile {
+                data: b"alias l=ls\n".to_vec(),
+                source: Some(b"alias l={{ ls }}\n".to_vec()),
+            },
+            ExportedFile {
+                data: b"dots/vimrc".to_vec(),
+                source: None,
+            },
+        ],
+        true,
+    )?;
+    assert_eq!(
+        0o600,
+        fs::metadata(&tarball).expect("stat tarball").mode() & 0o777
+    );
+
+    // files and manifest
+    let mut files = read_tree(&tarball)?;
+    let read_manifest = take_manifest(&mut files)?.expect("manifest in tarball");
+    assert_eq!("home", read_manifest.version);
+    assert_eq!("~/.vimrc", read_manifest.configs[1].path);
+    assert!(read_manifest.configs[0].template && read_manifest.configs[0].encrypted);
+    assert_eq!(manifest.variables, read_manifest.variables);
+    assert_eq!(3, files.len());
+    assert_eq!(
+        (".templates/home/.bashrc", b"alias l={{ ls }}\n".to_vec()),
+        (files[0].file.as_str(), files[0].data.clone())
+    );
+    assert_eq!(
+        (
+            "home/.bashrc",
+            ConfigKind::File,
+            Some(0o600),
+            Some(1_600_000_000)
+        ),
+        (
+            files[1].file.as_str(),
+            files[1].kind,
+            files[1].mode,
+            files[1].mtime
+        )
+    );
+    assert_eq!(b"alias l=ls\n".to_vec(), files[1].data);
+    assert_eq!(
+        (ConfigKind::Symlink, b"dots/vimrc".to_vec()),
+        (files[2].kind, files[2].data.clone())
+    );
+
+    // stow packages
+    assert_eq!(Some(".bashrc".to_string()), stow_path("bash/dot-bashrc"));
+    assert_eq!(
+        Some(".config/nvim/init.lua".to_string()),
+        stow_path("nvim/dot-config/nvim/init.lua")
+    );
+    assert_eq!(None, stow_path("README.md"));
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
@@ -1,34 +1,183 @@
-extern crate base64;
-
-use base64::{decode, encode};
-use rusqlite::{Connection, Result, Row, NO_PARAMS};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, Null, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{Connection, Result, Row, Transaction, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 /// Entity representing config stored in db
-#[derive(Debug)]
+/// (file metadata is None for configs stored before it was recorded)
+#[derive(Debug, Default)]
 pub struct Config {
     pub id: i32,
     pub version_id: i32,
     pub path: String,
-    pub data: Vec<String>,
+    pub data: Vec<u8>,
+    /// permission bits of file (e.g. 0o755)
+    pub mode: Option<u32>,
+    /// owner user id of file
+    pub uid: Option<u32>,
+    /// owner group id of file
+    pub gid: Option<u32>,
+    /// modification time of file (unix timestamp)
+    pub mtime: Option<i64>,
+    /// what is stored in data, file contents or symlink target
+    pub kind: ConfigKind,
+    /// data contains {{ variable }} placeholders rendered on write
+    pub template: bool,
+    /// data is encrypted by passphrase or key file (see secret module)
+    pub encrypted: bool,
+}
+
+/// kind of filesystem entry config represents
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigKind {
+    /// regular file (symlinks are followed), data holds its contents
+    #[default]
+    File,
+    /// symlink kept as link, data holds its target
+    Symlink,
+}
+
+impl ConfigKind {
+    /// name stored in db
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigKind::File => "file",
+            ConfigKind::Symlink => "symlink",
+        }
+    }
+}
+
+impl FromStr for ConfigKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> std::result::Result<Self, Self::Err> {
+        match kind {
+            "file" => Ok(ConfigKind::File),
+            "symlink" => Ok(ConfigKind::Symlink),
+            _ => Err(format!(
+                "unknown config kind {}, options: file, symlink",
+                kind
+            )),
+        }
+    }
+}
+
+impl ToSql for ConfigKind {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for ConfigKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
 }
 
 /// Entity representing version of configs
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Version {
     pub id: i32,
     pub name: String,
+    /// version this one is layered on, its configs apply unless overridden by path
+    pub parent_id: Option<i32>,
+}
+
+/// Entity representing snapshot of config data taken at some point in time
+#[derive(Debug)]
+pub struct Revision {
+    pub id: i32,
+    pub config_id: i32,
+    pub data: Vec<u8>,
+    pub hash: String,
+    pub hostname: String,
+    pub created_at: i64,
+}
+
+/// Entity representing content hash of config at last sync on some machine
+/// (base telling which side changed since then)
+#[derive(Debug)]
+pub struct SyncState {
+    pub id: i32,
+    pub config_id: i32,
+    pub hostname: String,
+    pub hash: String,
+}
+
+/// Entity representing directory whose files are tracked as configs,
+/// files created in it later are picked up by read
+#[derive(Debug)]
+pub struct TrackedDir {
+    pub id: i32,
+    pub version_id: i32,
+    pub path: String,
+    /// newline separated globs (relative to path) files have to match, empty matches all
+    pub include: String,
+    /// newline separated globs (relative to path) of files to skip
+    pub exclude: String,
+}
+
+/// Entity representing contents of config location before write overwrote it,
+/// backups of one command share batch number (restored together by undo)
+#[derive(Debug)]
+pub struct Backup {
+    pub id: i32,
+    pub batch: i32,
+    /// path of config as stored (may need expansion)
+    pub path: String,
+    pub kind: ConfigKind,
+    /// previous contents (or symlink target), None if nothing existed there
+    pub data: Option<Vec<u8>>,
+    pub hostname: String,
+    pub created_at: i64,
+    /// data is encrypted (backup of encrypted config)
+    pub encrypted: bool,
+    /// config written (None for backups made before it was recorded)
+    pub config_id: Option<i32>,
+    /// hash of sync base of config on this machine before write, None if there was none
+    pub base_hash: Option<String>,
+    /// hash of contents written, undo refuses to overwrite file changed since
+    /// (None for backups made before it was recorded)
+    pub written_hash: Option<String>,
+}
+
+/// Entity representing variable of version rendered into template configs
+#[derive(Debug)]
+pub struct Variable {
+    pub id: i32,
+    pub version_id: i32,
+    pub name: String,
+    pub value: String,
+}
+
+/// Entity representing machine bound to its default version
+#[derive(Debug)]
+pub struct Machine {
+    pub id: i32,
+    pub hostname: String,
+    pub version_id: i32,
+}
+
+/// closure building entity instance out of selected row
+pub type Builder<T> = Box<dyn FnMut(&Row<'_>) -> Result<T>>;
+
+/// bound value of nullable column
+fn optional<'v, T: Into<ToSqlOutput<'v>>>(value: Option<T>) -> ToSqlOutput<'v> {
+    value.map_or_else(|| Null.into(), Into::into)
 }
 
 pub trait Entity<'a> {
     /// name of the table (statically defined)
     fn table_name() -> &'static str;
-    /// types of data fields (statically defined)
-    fn types() -> &'static str;
     /// columns representing fields of entity, for create (statically defined)
     fn columns() -> &'static str;
 
     /// builds instance of Entity
-    fn builder() -> Box<dyn FnMut(&Row<'_>) -> Result<Self>>
+    fn builder() -> Builder<Self>
     where
         Self: Sized;
 
@@ -40,8 +189,9 @@ pub trait Entity<'a> {
         Self::select(db, Self::columns(), Self::builder())
     }
 
-    /// fetches fields of entity passed in query matching where clause and returns Vec<Self>
-    fn select_where(db: &Connection, condition: &str) -> Result<Vec<Self>>
+    /// fetches fields of entity matching where clause and returns Vec<Self>
+    /// condition references bound parameters as ?1, ?2, ... (e.g. "name=?1")
+    fn select_where(db: &Connection, condition: &str, params: &[&dyn ToSql]) -> Result<Vec<Self>>
     where
         Self: Sized,
     {
@@ -51,10 +201,8 @@ pub trait Entity<'a> {
             Self::table_name(),
             condition
         ))?;
-        let results = stmt.query_map(NO_PARAMS, Self::builder())?;
-
-        let rows: Vec<Self> = results.into_iter().map(|e| e.unwrap()).collect();
-        Ok(rows)
+        let results = stmt.query_map(params, Self::builder())?;
+        results.collect()
     }
 
     /// return entity instance by its id (general part)
@@ -63,12 +211,11 @@ pub trait Entity<'a> {
         Self: Sized,
     {
         let mut stmt = db.prepare(&format!(
-            "SELECT {} FROM {} WHERE id={}",
+            "SELECT {} FROM {} WHERE id=?1",
             Self::columns(),
-            Self::table_name(),
-            id
+            Self::table_name()
         ))?;
-        stmt.query_row(NO_PARAMS, Self::builder())
+        stmt.query_row([id], Self::builder())
     }
 
     fn next_id(db: &'a Connection) -> Result<i32> {
@@ -78,71 +225,58 @@ pub trait Entity<'a> {
         ))?;
         let highest_id: Option<i32> = stmt
             .query_map(NO_PARAMS, |row| row.get(0))?
-            .into_iter()
-            .map(|e| e.unwrap())
-            .nth(0);
+            .next()
+            .transpose()?;
         match highest_id {
             Some(id) => Ok(id + 1),
             None => Ok(1),
         }
     }
-    /// values during create, has to have the same number of items
-    /// separated by comma as self::columns
-    fn values(&self) -> String;
+    /// values bound during create, has to have the same number of items
+    /// (and the same order) as self::columns
+    fn values(&self) -> Vec<ToSqlOutput<'_>>;
 
     /// creates db instance of entity
-    fn create(&self, db: &'a Connection) -> Result<()> {
+    /// (changes are made only inside transaction, so commands are all-or-nothing)
+    fn create(&self, db: &Transaction) -> Result<()> {
+        let values = self.values();
+        let placeholders: Vec<String> = (1..=values.len()).map(|i| format!("?{}", i)).collect();
         db.execute(
             &format!(
                 "INSERT INTO {} ({}) VALUES ({})",
                 Self::table_name(),
                 Self::columns(),
-                Self::values(&self)
+                placeholders.join(", ")
             ),
-            NO_PARAMS,
+            values,
         )?;
         Ok(())
     }
 
-    /// delete entity from db
-    fn delete(db: &'a Connection, parameter: &str, value: &str, operator: &str) -> Result<()> {
+    /// delete entities matching where clause from db
+    /// condition references bound parameters as ?1, ?2, ... (e.g. "id=?1")
+    fn delete(db: &Transaction, condition: &str, params: &[&dyn ToSql]) -> Result<()> {
         db.execute(
-            &format!(
-                "DELETE FROM {} WHERE {}{}{}",
-                Self::table_name(),
-                parameter,
-                operator,
-                value
-            ),
-            NO_PARAMS,
-        )?;
-        Ok(())
-    }
-
-    /// update entity in db
-    fn update(db: &'a Connection, id: i32, updated_column: &str, new_value: &str) -> Result<()> {
-        db.execute(
-            &format!(
-                "UPDATE {} SET {}='{}' WHERE id={}",
-                Self::table_name(),
-                updated_column,
-                new_value,
-                id
-            ),
-            NO_PARAMS,
+            &format!("DELETE FROM {} WHERE {}", Self::table_name(), condition),
+            params,
         )?;
         Ok(())
     }
 
-    /// creates table in the database according to table_name and data types
-    fn table(db: &'a Connection) -> Result<()> {
+    /// update entity in db, new value is bound as parameter
+    fn update(
+        db: &Transaction,
+        id: i32,
+        updated_column: &str,
+        new_value: &dyn ToSql,
+    ) -> Result<()> {
         db.execute(
             &format!(
-                "CREATE TABLE IF NOT EXISTS {} {}",
+                "UPDATE {} SET {}=?1 WHERE id=?2",
                 Self::table_name(),
-                Self::types()
+                updated_column
             ),
-            NO_PARAMS,
+            &[new_value, &id],
         )?;
         Ok(())
     }
@@ -155,9 +289,7 @@ pub trait Entity<'a> {
     {
         let mut stmt = db.prepare(&format!("SELECT {} FROM {}", query, Self::table_name()))?;
         let results = stmt.query_map(NO_PARAMS, f)?;
-
-        let rows: Vec<Self> = results.into_iter().map(|e| e.unwrap()).collect();
-        Ok(rows)
+        results.collect()
     }
 }
 
@@ -166,40 +298,40 @@ impl<'a> Entity<'a> for Config {
     fn table_name() -> &'static str {
         "configs"
     }
-    fn types() -> &'static str {
-        "(id PRIMARY KEY,
-        path TEXT NOT NULL,
-        data TEXT NOT NULL,
-        version_id INTEGER NOT NULL,
-        FOREIGN KEY (version_id) REFERENCES versions(id)
-        )"
-    }
     fn columns() -> &'static str {
-        "id, path, data, version_id"
+        "id, path, data, version_id, mode, uid, gid, mtime, kind, template, encrypted"
     }
 
-    fn values(&self) -> String {
-        format!(
-            "{}, '{}', '{}', {}",
-            self.id,
-            self.path,
-            encode(self.data.join("\n")),
-            self.version_id
-        )
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.path.as_str().into(),
+            self.data.as_slice().into(),
+            self.version_id.into(),
+            optional(self.mode),
+            optional(self.uid),
+            optional(self.gid),
+            optional(self.mtime),
+            self.kind.as_str().into(),
+            self.template.into(),
+            self.encrypted.into(),
+        ]
     }
     /// builds instance of Config
-    fn builder() -> Box<dyn FnMut(&Row<'_>) -> Result<Self>> {
+    fn builder() -> Builder<Self> {
         Box::new(|row: &Row| {
-            let data: String = row.get(2)?;
             Ok(Config {
                 id: row.get(0)?,
                 path: row.get(1)?,
-                data: decode_lines(data)
-                    .split('\n')
-                    .into_iter()
-                    .map(|x| x.to_string())
-                    .collect(),
+                data: row.get(2)?,
                 version_id: row.get(3)?,
+                mode: row.get(4)?,
+                uid: row.get(5)?,
+                gid: row.get(6)?,
+                mtime: row.get(7)?,
+                kind: row.get(8)?,
+                template: row.get(9)?,
+                encrypted: row.get(10)?,
             })
         })
     }
@@ -211,30 +343,223 @@ impl<'a> Entity<'a> for Version {
         "versions"
     }
     fn columns() -> &'static str {
-        "id, name"
-    }
-    fn types() -> &'static str {
-        "(id PRIMARY KEY,
-        name TEXT NOT NULL)"
+        "id, name, parent_id"
     }
 
-    fn values(&self) -> String {
-        format!("{}, '{}'", self.id, self.name)
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.name.as_str().into(),
+            optional(self.parent_id),
+        ]
     }
     /// builds instance of Version
-    fn builder() -> Box<dyn FnMut(&Row<'_>) -> Result<Self>> {
+    fn builder() -> Builder<Self> {
         Box::new(|row: &Row| {
             Ok(Version {
                 id: row.get(0)?,
                 name: row.get(1)?,
+                parent_id: row.get(2)?,
             })
         })
     }
 }
 
-/// helper method decoding lines from base64 back to newline separated lines
-fn decode_lines(encoded_lines: String) -> String {
-    let decoded_lines = decode(encoded_lines).expect("base64 decode failed");
-    let string_lines = std::str::from_utf8(&decoded_lines).expect("utf8 -> str conversion failed");
-    string_lines.to_string()
+/// implementation of Entity trait for Revision
+impl<'a> Entity<'a> for Revision {
+    fn table_name() -> &'static str {
+        "config_revisions"
+    }
+    fn columns() -> &'static str {
+        "id, config_id, data, hash, hostname, created_at"
+    }
+
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.config_id.into(),
+            self.data.as_slice().into(),
+            self.hash.as_str().into(),
+            self.hostname.as_str().into(),
+            self.created_at.into(),
+        ]
+    }
+    /// builds instance of Revision
+    fn builder() -> Builder<Self> {
+        Box::new(|row: &Row| {
+            Ok(Revision {
+                id: row.get(0)?,
+                config_id: row.get(1)?,
+                data: row.get(2)?,
+                hash: row.get(3)?,
+                hostname: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })
+    }
+}
+
+/// implementation of Entity trait for SyncState
+impl<'a> Entity<'a> for SyncState {
+    fn table_name() -> &'static str {
+        "sync_states"
+    }
+    fn columns() -> &'static str {
+        "id, config_id, hostname, hash"
+    }
+
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.config_id.into(),
+            self.hostname.as_str().into(),
+            self.hash.as_str().into(),
+        ]
+    }
+    /// builds instance of SyncState
+    fn builder() -> Builder<Self> {
+        Box::new(|row: &Row| {
+            Ok(SyncState {
+                id: row.get(0)?,
+                config_id: row.get(1)?,
+                hostname: row.get(2)?,
+                hash: row.get(3)?,
+            })
+        })
+    }
+}
+
+/// implementation of Entity trait for TrackedDir
+impl<'a> Entity<'a> for TrackedDir {
+    fn table_name() -> &'static str {
+        "tracked_dirs"
+    }
+    fn columns() -> &'static str {
+        "id, path, version_id, include, exclude"
+    }
+
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.path.as_str().into(),
+            self.version_id.into(),
+            self.include.as_str().into(),
+            self.exclude.as_str().into(),
+        ]
+    }
+    /// builds instance of TrackedDir
+    fn builder() -> Builder<Self> {
+        Box::new(|row: &Row| {
+            Ok(TrackedDir {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                version_id: row.get(2)?,
+                include: row.get(3)?,
+                exclude: row.get(4)?,
+            })
+        })
+    }
+}
+
+/// implementation of Entity trait for Backup
+impl<'a> Entity<'a> for Backup {
+    fn table_name() -> &'static str {
+        "backups"
+    }
+    fn columns() -> &'static str {
+        "id, batch, path, kind, data, hostname, created_at, encrypted, config_id, base_hash,
+        written_hash"
+    }
+
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.batch.into(),
+            self.path.as_str().into(),
+            self.kind.as_str().into(),
+            optional(self.data.as_deref()),
+            self.hostname.as_str().into(),
+            self.created_at.into(),
+            self.encrypted.into(),
+            optional(self.config_id),
+            optional(self.base_hash.as_deref()),
+            optional(self.written_hash.as_deref()),
+        ]
+    }
+    /// builds instance of Backup
+    fn builder() -> Builder<Self> {
+        Box::new(|row: &Row| {
+            Ok(Backup {
+                id: row.get(0)?,
+                batch: row.get(1)?,
+                path: row.get(2)?,
+                kind: row.get(3)?,
+                data: row.get(4)?,
+                hostname: row.get(5)?,
+                created_at: row.get(6)?,
+                encrypted: row.get(7)?,
+                config_id: row.get(8)?,
+                base_hash: row.get(9)?,
+                written_hash: row.get(10)?,
+            })
+        })
+    }
+}
+
+/// implementation of Entity trait for Machine
+impl<'a> Entity<'a> for Machine {
+    fn table_name() -> &'static str {
+        "machines"
+    }
+    fn columns() -> &'static str {
+        "id, hostname, version_id"
+    }
+
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.hostname.as_str().into(),
+            self.version_id.into(),
+        ]
+    }
+    /// builds instance of Machine
+    fn builder() -> Builder<Self> {
+        Box::new(|row: &Row| {
+            Ok(Machine {
+                id: row.get(0)?,
+                hostname: row.get(1)?,
+                version_id: row.get(2)?,
+            })
+        })
+    }
+}
+
+/// implementation of Entity trait for Variable
+impl<'a> Entity<'a> for Variable {
+    fn table_name() -> &'static str {
+        "variables"
+    }
+    fn columns() -> &'static str {
+        "id, version_id, name, value"
+    }
+
+    fn values(&self) -> Vec<ToSqlOutput<'_>> {
+        vec![
+            self.id.into(),
+            self.version_id.into(),
+            self.name.as_str().into(),
+            self.value.as_str().into(),
+        ]
+    }
+    /// builds instance of Variable
+    fn builder() -> Builder<Self> {
+        Box::new(|row: &Row| {
+            Ok(Variable {
+                id: row.get(0)?,
+                version_id: row.get(1)?,
+                name: row.get(2)?,
+                value: row.get(3)?,
+            })
+        })
+    }
 }
@@ -0,0 +1,136 @@
+use std::fmt;
+use std::io;
+
+/// crate-wide error returned by library operations
+#[derive(Debug)]
+pub enum Error {
+    /// sqlite failure
+    Db(rusqlite::Error),
+    /// filesystem failure on given path
+    Io(String, io::Error),
+    /// requested version, config or revision does not exist
+    NotFound(String),
+    /// operation clashes with existing state
+    Conflict(String),
+    /// stored or given data could not be decoded
+    Decode(String),
+    /// invalid combination or format of arguments
+    Usage(String),
+}
+
+/// result of library operations
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// wraps io error together with path it happened on
+    pub fn io(path: &str, source: io::Error) -> Error {
+        Error::Io(path.to_string(), source)
+    }
+
+    /// process exit code, distinct for every kind of error
+    /// (1 is left for commands reporting differences or conflicts)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 2,
+            Error::NotFound(_) => 3,
+            Error::Conflict(_) => 4,
+            Error::Decode(_) => 5,
+            Error::Io(_, _) => 6,
+            Error::Db(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Db(e) => write!(f, "database error: {}", e),
+            Error::Io(path, e) => write!(f, "{}: {}", path, e),
+            Error::NotFound(message) => write!(f, "not found: {}", message),
+            Error::Conflict(message) => write!(f, "conflict: {}", message),
+            Error::Decode(message) => write!(f, "could not decode {}", message),
+            Error::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Db(e) => Some(e),
+            Error::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                Error::NotFound("no matching record in db".to_string())
+            }
+            e @ (rusqlite::Error::InvalidColumnType(..)
+            | rusqlite::Error::FromSqlConversionFailure(..)) => {
+                Error::Decode(format!("value stored in db ({})", e))
+            }
+            rusqlite::Error::SqliteFailure(failure, message)
+                if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Error::Conflict(message.unwrap_or_else(|| failure.to_string()))
+            }
+            e => Error::Db(e),
+        }
+    }
+}
+
+/// testing that sqlite failures map to categories with their exit codes
+#[test]
+fn exit_codes() {
+    let db = rusqlite::Connection::open_in_memory().expect("open in-memory db");
+    db.execute_batch("CREATE TABLE versions (id INTEGER PRIMARY KEY, name TEXT UNIQUE);")
+        .expect("create table");
+    let insert = |id: i32| {
+        db.execute(
+            "INSERT INTO versions (id, name) VALUES (?1, 'home')",
+            rusqlite::params![id],
+        )
+    };
+    insert(1).expect("insert version");
+
+    // constraint violation
+    let error = Error::from(insert(2).expect_err("duplicate name"));
+    assert!(matches!(error, Error::Conflict(_)));
+    assert_eq!(4, error.exit_code());
+
+    // missing record
+    let missing = db.query_row(
+        "SELECT name FROM versions WHERE id=2",
+        rusqlite::NO_PARAMS,
+        |row| row.get::<_, String>(0),
+    );
+    let error = Error::from(missing.expect_err("no version 2"));
+    assert!(matches!(error, Error::NotFound(_)));
+    assert_eq!(3, error.exit_code());
+
+    // value that does not decode
+    let wrong_type = db.query_row("SELECT name FROM versions", rusqlite::NO_PARAMS, |row| {
+        row.get::<_, i64>(0)
+    });
+    let error = Error::from(wrong_type.expect_err("name is not integer"));
+    assert!(matches!(error, Error::Decode(_)));
+    assert_eq!(5, error.exit_code());
+
+    // other failures
+    let error = Error::from(
+        db.execute_batch("SELECT * FROM missing")
+            .expect_err("no table"),
+    );
+    assert!(matches!(error, Error::Db(_)));
+    assert_eq!(7, error.exit_code());
+    assert_eq!(
+        6,
+        Error::io("~/.bashrc", io::ErrorKind::NotFound.into()).exit_code()
+    );
+    assert_eq!(2, Error::Usage(String::new()).exit_code());
+}
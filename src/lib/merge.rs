@@ -0,0 +1,132 @@
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+
+/// label of disk side in conflict markers
+static OURS_LABEL: &str = "disk";
+/// label of db side in conflict markers
+static THEIRS_LABEL: &str = "db";
+
+/// result of three-way merge
+#[derive(Debug)]
+pub struct Merged {
+    /// merged text, conflicting hunks are wrapped in conflict markers
+    pub text: String,
+    /// number of conflicting hunks
+    pub conflicts: usize,
+}
+
+/// three-way line merge of two descendants (disk and db) of common base
+/// hunks changed only on one side are taken from that side,
+/// hunks changed on both sides differently are conflicts
+pub fn merge(base: &str, ours: &str, theirs: &str) -> Merged {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let ours_matches = matches(&base, &ours);
+    let theirs_matches = matches(&base, &theirs);
+
+    let mut merged = Merged {
+        text: String::new(),
+        conflicts: 0,
+    };
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    while b < base.len() || o < ours.len() || t < theirs.len() {
+        // next base line kept on both sides (or end of all three)
+        let (next_b, next_o, next_t) = (b..base.len())
+            .find_map(|i| match (ours_matches[i], theirs_matches[i]) {
+                (Some(oi), Some(ti)) => Some((i, oi, ti)),
+                _ => None,
+            })
+            .unwrap_or((base.len(), ours.len(), theirs.len()));
+
+        if (next_b, next_o, next_t) == (b, o, t) {
+            merged.text.push_str(base[b]);
+            b += 1;
+            o += 1;
+            t += 1;
+            continue;
+        }
+
+        let base_chunk = &base[b..next_b];
+        let ours_chunk = &ours[o..next_o];
+        let theirs_chunk = &theirs[t..next_t];
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            push_lines(&mut merged.text, theirs_chunk);
+        } else if theirs_chunk == base_chunk {
+            push_lines(&mut merged.text, ours_chunk);
+        } else {
+            merged.conflicts += 1;
+            push_marker(&mut merged.text, &format!("<<<<<<< {}", OURS_LABEL));
+            push_lines(&mut merged.text, ours_chunk);
+            push_marker(&mut merged.text, "=======");
+            push_lines(&mut merged.text, theirs_chunk);
+            push_marker(&mut merged.text, &format!(">>>>>>> {}", THEIRS_LABEL));
+        }
+        b = next_b;
+        o = next_o;
+        t = next_t;
+    }
+    merged
+}
+
+/// for every base line index of the same line in other text (if it was kept)
+fn matches(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut result = vec![None; base.len()];
+    for op in capture_diff_slices(Algorithm::Myers, base, other) {
+        if let DiffOp::Equal {
+            old_index,
+            new_index,
+            len,
+        } = op
+        {
+            for i in 0..len {
+                result[old_index + i] = Some(new_index + i);
+            }
+        }
+    }
+    result
+}
+
+/// appends lines to merged text
+fn push_lines(text: &mut String, lines: &[&str]) {
+    for line in lines {
+        text.push_str(line);
+    }
+}
+
+/// appends conflict marker on its own line
+fn push_marker(text: &mut String, marker: &str) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(marker);
+    text.push('\n');
+}
+
+/// testing merge of non-overlapping, identical and conflicting changes
+#[test]
+fn three_way_merge() {
+    let base = "a\nb\nc\nd\ne\n";
+
+    // changes in different places are combined
+    let merged = merge(base, "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\nf\n");
+    assert_eq!(0, merged.conflicts);
+    assert_eq!("A\nb\nc\nd\nE\nf\n", merged.text);
+
+    // the same change on both sides is taken once
+    let merged = merge(base, "a\nB\nc\nd\ne\n", "a\nB\nc\nd\ne\n");
+    assert_eq!(0, merged.conflicts);
+    assert_eq!("a\nB\nc\nd\ne\n", merged.text);
+
+    // different changes of the same line conflict
+    let merged = merge(base, "a\nb\nX\nd\ne\n", "a\nb\nY\nd\ne\n");
+    assert_eq!(1, merged.conflicts);
+    assert_eq!(
+        "a\nb\n<<<<<<< disk\nX\n=======\nY\n>>>>>>> db\nd\ne\n",
+        merged.text
+    );
+
+    // missing trailing newline is kept
+    let merged = merge("a\nb", "a\nb", "a\nc");
+    assert_eq!(0, merged.conflicts);
+    assert_eq!("a\nc", merged.text);
+}
@@ -0,0 +1,563 @@
+use rusqlite::{params, Connection, NO_PARAMS};
+
+#[allow(unused_imports)]
+use super::entities::{Config, Entity, Version};
+use super::{Error, Result};
+
+/// single step of database schema evolution
+pub struct Migration {
+    /// schema version database has after this step (stored in PRAGMA user_version)
+    pub version: i32,
+    /// what the step does, shown by migrate command
+    pub description: &'static str,
+    /// applies the step, runs inside transaction
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+/// all migrations in order they have to be applied
+/// (never change already released steps, append new ones instead)
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create configs and versions tables",
+        apply: create_base_tables,
+    },
+    Migration {
+        version: 2,
+        description: "convert base64 encoded config data to blobs",
+        apply: convert_legacy_data,
+    },
+    Migration {
+        version: 3,
+        description: "create config_revisions table",
+        apply: create_revisions_table,
+    },
+    Migration {
+        version: 4,
+        description: "create sync_states table",
+        apply: create_sync_states_table,
+    },
+    Migration {
+        version: 5,
+        description: "create tracked_dirs table",
+        apply: create_tracked_dirs_table,
+    },
+    Migration {
+        version: 6,
+        description: "add file metadata columns to configs",
+        apply: add_config_metadata,
+    },
+    Migration {
+        version: 7,
+        description: "add kind column to configs (file or symlink)",
+        apply: add_config_kind,
+    },
+    Migration {
+        version: 8,
+        description: "create backups table",
+        apply: create_backups_table,
+    },
+    Migration {
+        version: 9,
+        description: "create machines table",
+        apply: create_machines_table,
+    },
+    Migration {
+        version: 10,
+        description: "add parent_id column to versions",
+        apply: add_version_parent,
+    },
+    Migration {
+        version: 11,
+        description: "add template column to configs and create variables table",
+        apply: create_templates,
+    },
+    Migration {
+        version: 12,
+        description: "add encrypted column to configs and backups",
+        apply: add_encryption,
+    },
+    Migration {
+        version: 13,
+        description: "enforce foreign keys with cascading deletes and unique names and paths",
+        apply: enforce_constraints,
+    },
+    Migration {
+        version: 14,
+        description: "add config_id and base_hash columns to backups",
+        apply: add_backup_base,
+    },
+    Migration {
+        version: 15,
+        description: "add written_hash column to backups",
+        apply: add_backup_written_hash,
+    },
+];
+
+/// schema version of database (0 for new or pre-migration databases)
+pub fn schema_version(db: &Connection) -> Result<i32> {
+    let version: i32 = db.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    Ok(version)
+}
+
+/// schema version this binary works with
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// migrations not yet applied to database
+pub fn pending(db: &Connection) -> Result<Vec<&'static Migration>> {
+    let version = schema_version(db)?;
+    if version > latest_version() {
+        return Err(Error::Conflict(format!(
+            "database schema version {} is newer than supported {}, upgrade rusty-configs",
+            version,
+            latest_version()
+        )));
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+        .collect())
+}
+
+/// applies pending migrations, each one in its own transaction
+/// returns applied migrations
+/// (foreign keys are not enforced meanwhile, so tables can be rebuilt)
+pub fn migrate(db: &Connection) -> Result<Vec<&'static Migration>> {
+    let pending = pending(db)?;
+    if pending.is_empty() {
+        return Ok(pending);
+    }
+    let foreign_keys: bool = db.query_row("PRAGMA foreign_keys", NO_PARAMS, |row| row.get(0))?;
+    db.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let applied = apply_all(db, &pending);
+    db.execute_batch(&format!("PRAGMA foreign_keys = {};", foreign_keys))?;
+    applied?;
+    Ok(pending)
+}
+
+/// applies migrations in order, stops at first failing one
+fn apply_all(db: &Connection, migrations: &[&Migration]) -> Result<()> {
+    for migration in migrations {
+        let tx = db.unchecked_transaction()?;
+        (migration.apply)(&tx)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", migration.version))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// true if database has tables (from init, even by versions before migrations existed)
+pub fn is_initialized(db: &Connection) -> Result<bool> {
+    if schema_version(db)? > 0 {
+        return Ok(true);
+    }
+    let tables: i32 = db.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name=?1",
+        params![Config::table_name()],
+        |row| row.get(0),
+    )?;
+    Ok(tables > 0)
+}
+
+/// 1: tables as created by init before migrations existed
+fn create_base_tables(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS configs (id PRIMARY KEY,
+        path TEXT NOT NULL,
+        data BLOB NOT NULL,
+        version_id INTEGER NOT NULL,
+        FOREIGN KEY (version_id) REFERENCES versions(id)
+        );
+        CREATE TABLE IF NOT EXISTS versions (id PRIMARY KEY,
+        name TEXT NOT NULL);",
+    )
+}
+
+/// 2: converts config data stored by older versions (base64 encoded text)
+/// into raw bytes, rows already holding blobs are left untouched
+/// and text that is not valid base64 is kept as is
+fn convert_legacy_data(db: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = db.prepare("SELECT id, data FROM configs WHERE typeof(data)='text'")?;
+    let legacy: Vec<(i32, String)> = stmt
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for (id, encoded) in legacy {
+        let data: Vec<u8> = base64::decode(&encoded).unwrap_or_else(|_| encoded.into_bytes());
+        db.execute("UPDATE configs SET data=?1 WHERE id=?2", params![data, id])?;
+    }
+    Ok(())
+}
+
+/// 3: snapshots of config data
+fn create_revisions_table(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS config_revisions (id PRIMARY KEY,
+        config_id INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        hash TEXT NOT NULL,
+        hostname TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        FOREIGN KEY (config_id) REFERENCES configs(id)
+        );",
+    )
+}
+
+/// 4: hashes of config data at last sync per machine
+fn create_sync_states_table(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS sync_states (id PRIMARY KEY,
+        config_id INTEGER NOT NULL,
+        hostname TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        FOREIGN KEY (config_id) REFERENCES configs(id)
+        );",
+    )
+}
+
+/// 5: directories whose new files are added on read
+fn create_tracked_dirs_table(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS tracked_dirs (id PRIMARY KEY,
+        path TEXT NOT NULL,
+        version_id INTEGER NOT NULL,
+        include TEXT NOT NULL,
+        exclude TEXT NOT NULL,
+        FOREIGN KEY (version_id) REFERENCES versions(id)
+        );",
+    )
+}
+
+/// 6: mode, owner, group and mtime of config files (NULL for existing rows)
+fn add_config_metadata(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE configs ADD COLUMN mode INTEGER;
+        ALTER TABLE configs ADD COLUMN uid INTEGER;
+        ALTER TABLE configs ADD COLUMN gid INTEGER;
+        ALTER TABLE configs ADD COLUMN mtime INTEGER;",
+    )
+}
+
+/// 7: configs can be symlinks storing their target, existing rows are files
+fn add_config_kind(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch("ALTER TABLE configs ADD COLUMN kind TEXT NOT NULL DEFAULT 'file';")
+}
+
+/// 8: previous contents of files overwritten by write commands
+fn create_backups_table(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS backups (id PRIMARY KEY,
+        batch INTEGER NOT NULL,
+        path TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        data BLOB,
+        hostname TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+        );",
+    )
+}
+
+/// 9: default version of machines
+fn create_machines_table(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS machines (id PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        version_id INTEGER NOT NULL,
+        FOREIGN KEY (version_id) REFERENCES versions(id)
+        );",
+    )
+}
+
+/// 10: versions layered on parent version
+fn add_version_parent(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch("ALTER TABLE versions ADD COLUMN parent_id INTEGER REFERENCES versions(id);")
+}
+
+/// 11: configs rendered with variables of version
+fn create_templates(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE configs ADD COLUMN template INTEGER NOT NULL DEFAULT 0;
+        CREATE TABLE IF NOT EXISTS variables (id PRIMARY KEY,
+        version_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        FOREIGN KEY (version_id) REFERENCES versions(id)
+        );",
+    )
+}
+
+/// 12: secret configs (and their backups) stored encrypted
+fn add_encryption(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE configs ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE backups ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;",
+    )
+}
+
+/// 13: tables rebuilt with foreign keys deleting dependent rows together with versions
+/// and configs, version names and paths of configs in version are unique
+/// (duplicate versions are merged into the oldest one, duplicate configs are dropped
+/// except the oldest one, rows referencing missing versions or configs are dropped)
+fn enforce_constraints(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "UPDATE versions SET parent_id=NULL WHERE parent_id NOT IN (SELECT id FROM versions);
+        DELETE FROM configs WHERE version_id NOT IN (SELECT id FROM versions);
+        DELETE FROM tracked_dirs WHERE version_id NOT IN (SELECT id FROM versions);
+        DELETE FROM machines WHERE version_id NOT IN (SELECT id FROM versions);
+        DELETE FROM variables WHERE version_id NOT IN (SELECT id FROM versions);
+        UPDATE versions SET parent_id=(SELECT MIN(id) FROM versions AS kept WHERE kept.name=
+            (SELECT name FROM versions AS parent WHERE parent.id=versions.parent_id))
+            WHERE parent_id IS NOT NULL;
+        UPDATE configs SET version_id=(SELECT MIN(id) FROM versions AS kept WHERE kept.name=
+            (SELECT name FROM versions WHERE id=configs.version_id));
+        UPDATE tracked_dirs SET version_id=(SELECT MIN(id) FROM versions AS kept WHERE kept.name=
+            (SELECT name FROM versions WHERE id=tracked_dirs.version_id));
+        UPDATE machines SET version_id=(SELECT MIN(id) FROM versions AS kept WHERE kept.name=
+            (SELECT name FROM versions WHERE id=machines.version_id));
+        UPDATE variables SET version_id=(SELECT MIN(id) FROM versions AS kept WHERE kept.name=
+            (SELECT name FROM versions WHERE id=variables.version_id));
+        DELETE FROM versions WHERE id NOT IN (SELECT MIN(id) FROM versions GROUP BY name);
+        UPDATE versions SET parent_id=NULL WHERE parent_id=id;
+        DELETE FROM configs WHERE id NOT IN (SELECT MIN(id) FROM configs GROUP BY path, version_id);
+        DELETE FROM config_revisions WHERE config_id NOT IN (SELECT id FROM configs);
+        DELETE FROM sync_states WHERE config_id NOT IN (SELECT id FROM configs);
+
+        CREATE TABLE versions_new (id PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        parent_id INTEGER REFERENCES versions(id)
+        );
+        INSERT INTO versions_new SELECT id, name, parent_id FROM versions;
+        DROP TABLE versions;
+        ALTER TABLE versions_new RENAME TO versions;
+
+        CREATE TABLE configs_new (id PRIMARY KEY,
+        path TEXT NOT NULL,
+        data BLOB NOT NULL,
+        version_id INTEGER NOT NULL,
+        mode INTEGER,
+        uid INTEGER,
+        gid INTEGER,
+        mtime INTEGER,
+        kind TEXT NOT NULL DEFAULT 'file',
+        template INTEGER NOT NULL DEFAULT 0,
+        encrypted INTEGER NOT NULL DEFAULT 0,
+        UNIQUE (path, version_id),
+        FOREIGN KEY (version_id) REFERENCES versions(id) ON DELETE CASCADE
+        );
+        INSERT INTO configs_new SELECT id, path, data, version_id, mode, uid, gid, mtime,
+            kind, template, encrypted FROM configs;
+        DROP TABLE configs;
+        ALTER TABLE configs_new RENAME TO configs;
+
+        CREATE TABLE config_revisions_new (id PRIMARY KEY,
+        config_id INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        hash TEXT NOT NULL,
+        hostname TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        FOREIGN KEY (config_id) REFERENCES configs(id) ON DELETE CASCADE
+        );
+        INSERT INTO config_revisions_new SELECT id, config_id, data, hash, hostname, created_at
+            FROM config_revisions;
+        DROP TABLE config_revisions;
+        ALTER TABLE config_revisions_new RENAME TO config_revisions;
+
+        CREATE TABLE sync_states_new (id PRIMARY KEY,
+        config_id INTEGER NOT NULL,
+        hostname TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        FOREIGN KEY (config_id) REFERENCES configs(id) ON DELETE CASCADE
+        );
+        INSERT INTO sync_states_new SELECT id, config_id, hostname, hash FROM sync_states;
+        DROP TABLE sync_states;
+        ALTER TABLE sync_states_new RENAME TO sync_states;
+
+        CREATE TABLE tracked_dirs_new (id PRIMARY KEY,
+        path TEXT NOT NULL,
+        version_id INTEGER NOT NULL,
+        include TEXT NOT NULL,
+        exclude TEXT NOT NULL,
+        FOREIGN KEY (version_id) REFERENCES versions(id) ON DELETE CASCADE
+        );
+        INSERT INTO tracked_dirs_new SELECT id, path, version_id, include, exclude
+            FROM tracked_dirs;
+        DROP TABLE tracked_dirs;
+        ALTER TABLE tracked_dirs_new RENAME TO tracked_dirs;
+
+        CREATE TABLE machines_new (id PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        version_id INTEGER NOT NULL,
+        FOREIGN KEY (version_id) REFERENCES versions(id) ON DELETE CASCADE
+        );
+        INSERT INTO machines_new SELECT id, hostname, version_id FROM machines;
+        DROP TABLE machines;
+        ALTER TABLE machines_new RENAME TO machines;
+
+        CREATE TABLE variables_new (id PRIMARY KEY,
+        version_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        FOREIGN KEY (version_id) REFERENCES versions(id) ON DELETE CASCADE
+        );
+        INSERT INTO variables_new SELECT id, version_id, name, value FROM variables;
+        DROP TABLE variables;
+        ALTER TABLE variables_new RENAME TO variables;",
+    )?;
+    let violations: i32 = db.query_row(
+        "SELECT COUNT(*) FROM pragma_foreign_key_check",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    match violations {
+        0 => Ok(()),
+        _ => Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT),
+            Some(format!("{} rows reference missing rows", violations)),
+        )),
+    }
+}
+
+/// 14: sync base of config before write, restored by undo
+/// (no foreign key, backups outlive deleted configs)
+fn add_backup_base(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE backups ADD COLUMN config_id INTEGER;
+        ALTER TABLE backups ADD COLUMN base_hash TEXT;",
+    )
+}
+
+/// 15: hash of contents written, compared with disk before undo
+fn add_backup_written_hash(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch("ALTER TABLE backups ADD COLUMN written_hash TEXT;")
+}
+
+/// testing that binary data and legacy base64 rows come back byte-for-byte
+#[test]
+fn binary_data() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrate(&db)?;
+    let db = db.transaction()?;
+    Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    }
+    .create(&db)?;
+    let bytes: Vec<u8> = vec![0, 159, 146, 150, b'\n', b'\n'];
+    Config {
+        id: 1,
+        version_id: 1,
+        path: "/tmp/binary".to_string(),
+        data: bytes.clone(),
+        ..Config::default()
+    }
+    .create(&db)?;
+    db.execute(
+        "INSERT INTO configs (id, path, data, version_id) VALUES (2, '/tmp/legacy', ?1, 1)",
+        params![base64::encode("no trailing newline")],
+    )?;
+
+    // conversion
+    convert_legacy_data(&db)?;
+    assert_eq!(bytes, Config::find(&db, 1)?.data);
+    assert_eq!(b"no trailing newline".to_vec(), Config::find(&db, 2)?.data);
+
+    Ok(())
+}
+
+/// testing upgrade of database created before migrations existed
+#[test]
+fn legacy_database_migration() -> Result<()> {
+    let db = Connection::open_in_memory()?;
+
+    // setup (schema of first release)
+    assert!(!is_initialized(&db)?);
+    db.execute_batch(
+        "CREATE TABLE configs (id PRIMARY KEY, path TEXT NOT NULL, data TEXT NOT NULL,
+        version_id INTEGER NOT NULL, FOREIGN KEY (version_id) REFERENCES versions(id));
+        CREATE TABLE versions (id PRIMARY KEY, name TEXT NOT NULL);
+        INSERT INTO versions (id, name) VALUES (1, 'home');
+        INSERT INTO configs (id, path, data, version_id) VALUES (1, '/tmp/test', 'aGk=', 1);",
+    )?;
+    assert!(is_initialized(&db)?);
+    assert_eq!(0, schema_version(&db)?);
+
+    // migrate
+    assert_eq!(MIGRATIONS.len(), migrate(&db)?.len());
+    assert_eq!(latest_version(), schema_version(&db)?);
+    assert_eq!(b"hi".to_vec(), Config::find(&db, 1)?.data);
+    assert!(pending(&db)?.is_empty());
+    assert!(migrate(&db)?.is_empty());
+
+    Ok(())
+}
+
+/// testing that duplicates and orphans are cleaned up and constraints enforced afterwards
+#[test]
+fn enforced_constraints() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup (duplicate version, duplicate config and orphaned rows of first release)
+    db.execute_batch(
+        "PRAGMA foreign_keys = OFF;
+        CREATE TABLE configs (id PRIMARY KEY, path TEXT NOT NULL, data TEXT NOT NULL,
+        version_id INTEGER NOT NULL, FOREIGN KEY (version_id) REFERENCES versions(id));
+        CREATE TABLE versions (id PRIMARY KEY, name TEXT NOT NULL);
+        INSERT INTO versions (id, name) VALUES (1, 'home'), (2, 'home'), (3, 'work');
+        INSERT INTO configs (id, path, data, version_id) VALUES
+            (1, '~/.bashrc', 'YQ==', 1), (2, '~/.vimrc', 'Yg==', 2),
+            (3, '~/.bashrc', 'Yw==', 2), (4, '~/.zshrc', 'ZA==', 9);",
+    )?;
+
+    // migrate
+    migrate(&db)?;
+    db.execute_batch("PRAGMA foreign_keys = ON;")?;
+    let db = db.transaction()?;
+    let versions: Vec<String> = Version::all(&db)?.into_iter().map(|v| v.name).collect();
+    assert_eq!(vec!["home", "work"], versions);
+    let configs: Vec<(i32, i32)> = Config::all(&db)?
+        .into_iter()
+        .map(|config| (config.id, config.version_id))
+        .collect();
+    assert_eq!(vec![(1, 1), (2, 1)], configs);
+
+    // violations
+    let duplicate = Version {
+        id: 4,
+        name: "work".to_string(),
+        ..Version::default()
+    };
+    assert!(matches!(
+        duplicate.create(&db).map_err(Error::from),
+        Err(Error::Conflict(_))
+    ));
+    let duplicate = Config {
+        id: 5,
+        version_id: 1,
+        path: "~/.vimrc".to_string(),
+        ..Config::default()
+    };
+    assert!(matches!(
+        duplicate.create(&db).map_err(Error::from),
+        Err(Error::Conflict(_))
+    ));
+    let orphan = Config {
+        id: 5,
+        version_id: 9,
+        path: "~/.zshrc".to_string(),
+        ..Config::default()
+    };
+    assert!(matches!(
+        orphan.create(&db).map_err(Error::from),
+        Err(Error::Conflict(_))
+    ));
+
+    // cascade
+    Version::delete(&db, "id=?1", params![1])?;
+    assert!(Config::all(&db)?.is_empty());
+
+    Ok(())
+}
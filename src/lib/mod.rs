@@ -1,276 +1,1196 @@
 extern crate base64;
 
-use base64::encode;
-
-use rusqlite::{Connection, Result};
-use std::collections::HashMap;
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rusqlite::types::ToSql;
+use rusqlite::{params, Action, Connection, DatabaseName, Transaction};
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
+use std::io::{self, ErrorKind, IsTerminal};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use walkdir::WalkDir;
 
+pub mod archive;
 pub mod entities;
+pub mod error;
+pub mod merge;
+pub mod migrations;
+pub mod output;
+pub mod paths;
+pub mod secret;
+pub mod template;
+
+pub use error::{Error, Result};
 
 #[allow(unused_imports)]
-use entities::{Config, Entity, Version};
+use entities::{
+    Backup, Config, ConfigKind, Entity, Machine, Revision, SyncState, TrackedDir, Variable, Version,
+};
+use output::Format;
+
+pub static DEFAULT_DB_LOCATION: &str = "db.sqlite";
+
+/// number of write batches per machine kept for undo
+static KEPT_BACKUP_BATCHES: i32 = 10;
 
-pub static DEFAULT_DB_LOCATION: &'static str = "db.sqlite";
+/// environment variable naming default version of machine
+pub static VERSION_VARIABLE: &str = "RUSTY_CONFIGS_VERSION";
+
+/// criteria narrowing down configs, unset fields match everything
+/// (unset version means default version of machine unless all_versions is set,
+/// see default_version)
+#[derive(Debug, Default)]
+pub struct Selector<'s> {
+    /// full path of config
+    pub path: Option<&'s str>,
+    /// name of config (last token of path separated by slash)
+    pub name: Option<&'s str>,
+    /// name of version config belongs to
+    pub version: Option<&'s str>,
+    /// glob matched against path of config (as stored or expanded)
+    pub glob: Option<&'s str>,
+    /// ignore default version of machine
+    pub all_versions: bool,
+}
 
 static DB_IS_FILE: bool = true;
 
+/// options of one run passed down to commands (instead of process-global state)
+#[derive(Debug, Default)]
+pub struct Context {
+    /// location of database file
+    pub db: String,
+    /// set by global --dry-run, commands work on in-memory copy of database
+    /// and only report files they would write
+    pub dry_run: bool,
+    /// passphrase or key file encrypted configs are encrypted with
+    pub secrets: secret::Secrets,
+}
+
 /// returns db connection (either temporary in memory or in file)
 /// determined by bool constant (mostly for development)
-fn get_db(db: &str) -> Connection {
-    match DB_IS_FILE {
-        true => Connection::open(db).expect("Could not open db"),
-        false => Connection::open_in_memory().unwrap(),
-    }
+fn open_db(db: &str) -> Result<Connection> {
+    let connection = match DB_IS_FILE {
+        true => Connection::open(db)?,
+        false => Connection::open_in_memory()?,
+    };
+    connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+    Ok(connection)
 }
 
-/// initializes tables of database
-pub fn init_db(db: &str) -> Result<()> {
-    let db = get_db(db);
-    Config::table(&db)?;
-    Version::table(&db)?;
-    println!("Db initialized with tables specified");
-    Ok(())
+/// returns db connection with schema migrated to the latest version
+/// (fails for databases not initialized yet)
+fn get_db(ctx: &Context) -> Result<Connection> {
+    let connection = open_initialized(ctx)?;
+    migrations::migrate(&connection)?;
+    if ctx.dry_run {
+        connection.update_hook(Some(row_change_reporter()));
+    }
+    Ok(connection)
 }
 
-/// updates path location of config (match by old one)
-pub fn update_config(db: &str, path: &str, version: &str, new_value: &str) -> std::io::Result<()> {
-    let db = get_db(db);
-    let version: Vec<Version> = Version::select_where(&db, &format!("name='{}'", version))
-        .expect("could not select version");
-    if version.len() == 0 {
-        panic!("No version with given name exists");
-    }
-    let matched_configs: Vec<Config> = Config::select_where(
-        &db,
-        &format!("path='{}' AND version_id={}", path, version[0].id),
-    )
-    .expect("Could not select any configs with condition");
-    if matched_configs.len() == 0 {
-        panic!("No config with given criteria exists");
+/// returns connection to existing initialized database, never creates file
+/// (in dry run connection to in-memory copy of it)
+fn open_initialized(ctx: &Context) -> Result<Connection> {
+    let db = ctx.db.as_str();
+    if DB_IS_FILE && !Path::new(db).exists() {
+        return Err(Error::NotFound(format!(
+            "database {} does not exist, create it with init",
+            db
+        )));
+    }
+    let connection = match ctx.dry_run {
+        true => {
+            let mut copy = Connection::open_in_memory()?;
+            copy.restore(
+                DatabaseName::Main,
+                db,
+                None::<fn(rusqlite::backup::Progress)>,
+            )?;
+            copy.execute_batch("PRAGMA foreign_keys = ON;")?;
+            copy
+        }
+        false => open_db(db)?,
+    };
+    if !migrations::is_initialized(&connection)? {
+        return Err(Error::NotFound(format!(
+            "database {} is not initialized, run init",
+            db
+        )));
     }
+    Ok(connection)
+}
+
+/// runs command on database in single transaction committed only when it succeeds,
+/// so failing command leaves database as it was
+fn in_transaction<T>(ctx: &Context, command: impl FnOnce(&Transaction) -> Result<T>) -> Result<T> {
+    all_or_nothing(&mut get_db(ctx)?, command)
+}
+
+/// runs command in transaction of connection, rolled back when command fails
+fn all_or_nothing<T>(
+    db: &mut Connection,
+    command: impl FnOnce(&Transaction) -> Result<T>,
+) -> Result<T> {
+    let tx = db.transaction()?;
+    let value = command(&tx)?;
+    tx.commit()?;
+    Ok(value)
+}
 
-    let options = ["path", "version"];
-    let splitted: Vec<&str> = new_value.split("=").collect();
-    let column = splitted[0];
-    let value = splitted[1];
+/// runs command writing files in single transaction committed only when it succeeds,
+/// failing command is rolled back except rows recording files it already wrote
+/// (backups created or dropped by undo and sync bases of their configs)
+fn in_transaction_keeping_writes<T>(
+    ctx: &Context,
+    command: impl FnOnce(&Transaction) -> Result<T>,
+) -> Result<T> {
+    keeping_writes(&mut get_db(ctx)?, command)
+}
 
-    if !options.contains(&column) {
-        panic!("Unknown column to update, options: path, version");
+/// runs command in transaction of connection, when it fails the transaction is rolled back
+/// and only backups and sync bases of files written meanwhile are applied again
+fn keeping_writes<T>(
+    db: &mut Connection,
+    command: impl FnOnce(&Transaction) -> Result<T>,
+) -> Result<T> {
+    let hostname = current_hostname();
+    let existing: Vec<Backup> = Backup::select_where(db, "hostname=?1", params![hostname])?;
+    let tx = db.transaction()?;
+    let error = match command(&tx) {
+        Ok(value) => {
+            tx.commit()?;
+            return Ok(value);
+        }
+        Err(error) => error,
+    };
+    let current: Vec<Backup> = Backup::select_where(&tx, "hostname=?1", params![hostname])?;
+    let before: HashSet<i32> = existing.iter().map(|backup| backup.id).collect();
+    let after: HashSet<i32> = current.iter().map(|backup| backup.id).collect();
+    let created: Vec<Backup> = current
+        .into_iter()
+        .filter(|backup| !before.contains(&backup.id))
+        .collect();
+    let dropped: Vec<Backup> = existing
+        .into_iter()
+        .filter(|backup| !after.contains(&backup.id))
+        .collect();
+    let mut bases: Vec<(i32, Option<String>)> = vec![];
+    for config_id in created
+        .iter()
+        .chain(&dropped)
+        .filter_map(|backup| backup.config_id)
+    {
+        bases.push((config_id, base_hash(&tx, config_id)?));
     }
-    if column == "version" {
-        let version: Vec<Version> = Version::select_where(&db, &format!("name='{}'", value))
-            .expect("could not select version");
-        let version_value: String = version[0].id.to_string();
-        for config in matched_configs {
-            Config::update(&db, config.id, "version_id", &version_value)
-                .expect("config version update failed");
+    tx.rollback()?;
+    let tx = db.transaction()?;
+    for backup in created {
+        backup.create(&tx)?;
+    }
+    for backup in dropped {
+        Backup::delete(&tx, "id=?1", params![backup.id])?;
+    }
+    for (config_id, hash) in bases {
+        if !Config::select_where(&tx, "id=?1", params![config_id])?.is_empty() {
+            set_base_hash(&tx, config_id, hash)?;
         }
-    } else {
-        for config in matched_configs {
-            Config::update(&db, config.id, column, value).expect("config path update failed");
+    }
+    tx.commit()?;
+    Err(error)
+}
+
+/// prints message about change made by command
+/// (left out in dry run, where nothing is changed)
+fn done(ctx: &Context, message: std::fmt::Arguments) {
+    if !ctx.dry_run {
+        println!("{}", message);
+    }
+}
+
+/// tables recording undo and sync state of written files, not reported in dry run
+static BOOKKEEPING_TABLES: &[&str] = &["backups", "sync_states"];
+
+/// hook printing row changes made to in-memory copy of database during dry run
+/// (first change of every row, bookkeeping tables left out)
+fn row_change_reporter() -> impl FnMut(Action, &str, &str, i64) + Send + 'static {
+    let mut reported: HashSet<(String, i64)> = HashSet::new();
+    move |action, _database, table, row_id| {
+        if BOOKKEEPING_TABLES.contains(&table) || !reported.insert((table.to_string(), row_id)) {
+            return;
         }
+        let change = match action {
+            Action::SQLITE_INSERT => "insert into",
+            Action::SQLITE_UPDATE => "update",
+            Action::SQLITE_DELETE => "delete from",
+            _ => "change",
+        };
+        println!("dry run: {} {} row {}", change, table, row_id);
+    }
+}
+
+/// initializes tables of database
+pub fn init_db(ctx: &Context) -> Result<()> {
+    if ctx.dry_run {
+        println!("dry run: database {} would be initialized", ctx.db);
+        return Ok(());
     }
-    println!("Config {} update successfull", column);
+    let db = open_db(&ctx.db)?;
+    migrations::migrate(&db)?;
+    println!("Db initialized with tables specified");
     Ok(())
 }
-/// updates name of version (match by old name)
-pub fn update_version(db: &str, name: &str, new_name: &str) -> Result<()> {
-    let db = get_db(db);
-    let versions: Vec<Version> =
-        Version::select_where(&db, &format!("name='{}'", name)).expect("could not select version");
-    if versions.len() == 0 {
-        panic!("No version matches criteria");
+
+/// applies pending schema migrations (only lists them in dry run)
+pub fn migrate(ctx: &Context) -> Result<()> {
+    let db = open_initialized(ctx)?;
+    let pending = migrations::pending(&db)?;
+    println!(
+        "Schema version {} (latest {})",
+        migrations::schema_version(&db)?,
+        migrations::latest_version()
+    );
+    if pending.is_empty() {
+        println!("No pending migrations");
+        return Ok(());
+    }
+    println!("Pending migrations:");
+    for (i, migration) in pending.iter().enumerate() {
+        let item = format!("{}. {}", migration.version, migration.description);
+        println!("{}", tree_item(i, pending.len(), 0, &item));
     }
-    for version in versions {
-        Version::update(&db, version.id, "name", &new_name)?
+    if !ctx.dry_run {
+        migrations::migrate(&db)?;
+        println!("All migrations applied");
     }
-    println!("Version name updated {} => {}", name, new_name);
     Ok(())
 }
 
-/// delete version by its name
-pub fn delete_version(db: &str, name: &str) -> std::io::Result<()> {
-    let db = get_db(db);
-    let versions: Vec<Version> =
-        Version::select_where(&db, &format!("name='{}'", name)).expect("could not select version");
-    if versions.len() == 0 {
-        panic!("No version matches criteria");
-    }
-    let version: &Version = &versions[0];
-    let configs: Vec<Config> = Config::select_where(&db, &format!("version_id={}", version.id))
-        .expect("could not select configs");
-    for config in configs {
-        Config::delete(&db, "id", &config.id.to_string(), "=").expect("Delete by id failed");
+/// updates path location of config (match by old one)
+pub fn update_config(ctx: &Context, path: &str, version: &str, new_value: &str) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let path = &paths::portable(path);
+        let version: Version = find_version(db, version)?;
+        let matched_configs: Vec<Config> =
+            Config::select_where(db, "path=?1 AND version_id=?2", params![path, version.id])?;
+        if matched_configs.is_empty() {
+            return Err(Error::NotFound(format!(
+                "config {} of version {}",
+                path, version.name
+            )));
+        }
+
+        let (column, value) = match new_value.split_once('=') {
+            Some((column, value))
+                if ["path", "version", "kind", "template", "encrypted"].contains(&column) =>
+            {
+                (column, value)
+            }
+            Some((column, _)) => {
+                return Err(Error::Usage(format!(
+                "Unknown column to update {}, options: path, version, kind, template, encrypted",
+                column
+            )))
+            }
+            None => {
+                return Err(Error::Usage(
+                    "Updated value has to be in format column=value".to_string(),
+                ))
+            }
+        };
+        if column == "version" {
+            let version: Version = find_version(db, value)?;
+            for config in matched_configs {
+                Config::update(db, config.id, "version_id", &version.id)?;
+            }
+        } else if column == "kind" {
+            // data of the other kind is read again (file contents vs symlink target)
+            let kind: ConfigKind = value.parse().map_err(Error::Usage)?;
+            for mut config in matched_configs {
+                if kind == ConfigKind::Symlink && (config.template || config.encrypted) {
+                    return Err(Error::Usage(format!(
+                        "{} is a template or encrypted, it can not be a symlink",
+                        config.path
+                    )));
+                }
+                config.kind = kind;
+                config.data = read_config(&config)?;
+                if config.encrypted {
+                    config.data = ctx.secrets.encrypt(&config.data)?;
+                }
+                record_metadata(&mut config)?;
+                Config::update(db, config.id, "kind", &config.kind)?;
+                Config::update(db, config.id, "data", &config.data)?;
+                store_metadata(db, &config)?;
+                snapshot(db, config.id, &config.data)?;
+                set_base(db, config.id, &config.data)?;
+            }
+        } else if column == "template" {
+            let template: bool = value.parse().map_err(|_| {
+                Error::Usage(format!("template has to be true or false, not {}", value))
+            })?;
+            for config in matched_configs {
+                if template && config.kind == ConfigKind::Symlink {
+                    return Err(Error::Usage(format!(
+                        "{} is a symlink, only files can be templates",
+                        config.path
+                    )));
+                }
+                Config::update(db, config.id, "template", &template)?;
+            }
+        } else if column == "encrypted" {
+            let encrypted: bool = value.parse().map_err(|_| {
+                Error::Usage(format!("encrypted has to be true or false, not {}", value))
+            })?;
+            for config in matched_configs {
+                if encrypted && config.kind == ConfigKind::Symlink {
+                    return Err(Error::Usage(format!(
+                        "{} is a symlink, only files can be encrypted",
+                        config.path
+                    )));
+                }
+                if config.encrypted != encrypted {
+                    set_encrypted(ctx, db, &config, encrypted)?;
+                }
+            }
+        } else {
+            let value = paths::portable(value);
+            for config in matched_configs {
+                Config::update(db, config.id, column, &value)?;
+            }
+        }
+        done(ctx, format_args!("Config {} update successfull", column));
+        Ok(())
+    })
+}
+/// updates version (match by old name): sets (Some(None) removes) its parent
+/// and renames it, both or nothing
+pub fn update_version(
+    ctx: &Context,
+    name: &str,
+    new_name: Option<&str>,
+    parent: Option<Option<&str>>,
+) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let version: Version = find_version(db, name)?;
+        if let Some(parent) = parent {
+            set_parent(ctx, db, &version, parent)?;
+        }
+        if let Some(new_name) = new_name {
+            if !Version::select_where(db, "name=?1", params![new_name])?.is_empty() {
+                return Err(Error::Conflict(format!(
+                    "version {} already exists",
+                    new_name
+                )));
+            }
+            Version::update(db, version.id, "name", &new_name)?;
+            done(
+                ctx,
+                format_args!("Version name updated {} => {}", name, new_name),
+            );
+        }
+        Ok(())
+    })
+}
+
+/// sets (or with None removes) parent version of version
+fn set_parent(
+    ctx: &Context,
+    db: &Transaction,
+    version: &Version,
+    parent: Option<&str>,
+) -> Result<()> {
+    match parent {
+        Some(parent) => {
+            let chain = version_chain(db, parent)?;
+            if chain.iter().any(|layer| layer.id == version.id) {
+                return Err(Error::Conflict(format!(
+                    "version {} is layered on {} already, parent would make a cycle",
+                    parent, version.name
+                )));
+            }
+            Version::update(db, version.id, "parent_id", &chain[0].id)?;
+            done(
+                ctx,
+                format_args!("Version {} layered on {}", version.name, parent),
+            );
+        }
+        None => {
+            Version::update(db, version.id, "parent_id", &None::<i32>)?;
+            done(ctx, format_args!("Version {} has no parent", version.name));
+        }
     }
-    Version::delete(&db, "id", &version.id.to_string(), "=").expect("Delete by id failed");
     Ok(())
 }
 
+/// delete version by its name
+pub fn delete_version(ctx: &Context, name: &str) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let version: Version = find_version(db, name)?;
+        let children: Vec<Version> =
+            Version::select_where(db, "parent_id=?1", params![version.id])?;
+        if !children.is_empty() {
+            let names: Vec<&str> = children.iter().map(|child| child.name.as_str()).collect();
+            return Err(Error::Conflict(format!(
+                "version {} is parent of {}, change their parent first",
+                version.name,
+                names.join(", ")
+            )));
+        }
+        // configs, tracked directories, bindings and variables are deleted by cascade
+        Version::delete(db, "id=?1", params![version.id])?;
+        Ok(())
+    })
+}
+
 /// delete config by its id
-pub fn delete_by_id(db: &str, id: u64) -> std::io::Result<()> {
-    let db = get_db(db);
-    Config::delete(&db, "id", &id.to_string(), "=").expect("Delete by id failed");
-    Ok(())
+pub fn delete_by_id(ctx: &Context, id: u64) -> Result<()> {
+    in_transaction(ctx, |db| {
+        delete_configs(db, "id=?1", params![id as i64])?;
+        Ok(())
+    })
 }
 
-/// delete config by its full path
-pub fn delete_by_path(db: &str, path: &str) -> std::io::Result<()> {
-    let db = get_db(db);
-    Config::delete(&db, "path", &format!("\"{}\"", path), "=").expect("Delete by path failed");
-    Ok(())
+/// delete config by its full path (or stop tracking directory with that path)
+pub fn delete_by_path(ctx: &Context, path: &str) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let path = &paths::portable(path);
+        delete_configs(db, "path=?1", params![path])?;
+        TrackedDir::delete(db, "path=?1", params![path.trim_end_matches('/')])?;
+        Ok(())
+    })
 }
 
 /// delete config by its name (last token separated by slash)
-pub fn delete_by_name(db: &str, name: &str) -> std::io::Result<()> {
-    let db = get_db(db);
-    Config::delete(&db, "path", &format!("'%{}'", name), " LIKE ").expect("Delete by name failed");
-    Ok(())
+pub fn delete_by_name(ctx: &Context, name: &str) -> Result<()> {
+    in_transaction(ctx, |db| {
+        delete_configs(db, "path LIKE ?1 ESCAPE '\\'", params![like_suffix(name)])?;
+        Ok(())
+    })
 }
 
 /// adds new config to database
-pub fn add_config(db: &str, path: &str, version: &str) -> std::io::Result<()> {
-    let db = get_db(db);
-    let file_lines = fs::read_to_string(path).expect("could not read file in db");
-    let new_id: i32 = Config::next_id(&db).expect("could not fetch next id");
+/// symlinks are stored as links (recreated by write) unless follow is set
+/// absolute paths under home are stored in portable form (see paths::portable)
+pub fn add_config(
+    ctx: &Context,
+    path: &str,
+    version: &str,
+    follow: bool,
+    template: bool,
+    encrypt: bool,
+) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let path = &paths::portable(path);
+        let location = paths::expand(path)?;
+        if Path::new(&location).is_dir() && (follow || !is_symlink(&location)) {
+            return Err(Error::Usage(format!(
+                "{} is a directory, add it with --recursive",
+                path
+            )));
+        }
+        if (template || encrypt) && !follow && is_symlink(&location) {
+            return Err(Error::Usage(format!(
+                "{} is a symlink, only files can be templates or encrypted",
+                path
+            )));
+        }
+        let version: Version = find_version(db, version)?;
+        if !Config::select_where(db, "path=?1 AND version_id=?2", params![path, version.id])?
+            .is_empty()
+        {
+            return Err(Error::Conflict(format!(
+                "config {} is already in version {}, refresh it with read",
+                path, version.name
+            )));
+        }
+        let config = insert_config(ctx, db, path, version.id, follow, encrypt)?;
+        if template {
+            Config::update(db, config.id, "template", &true)?;
+        }
+        done(ctx, format_args!("Config record in database created"));
+        Ok(())
+    })
+}
 
-    let versions: Vec<Version> = Version::select_where(&db, &format!("name='{}'", version))
-        .expect("could not select version");
-    if versions.len() == 0 {
-        panic!("No version matches criteria");
-    }
+/// adds every file of directory (recursively) to database and keeps tracking it,
+/// so files created in it later are added by read
+/// include / exclude are globs relative to the directory (e.g. "*.lua", "plugin/**")
+pub fn add_directory(
+    ctx: &Context,
+    path: &str,
+    version: &str,
+    include: &[&str],
+    exclude: &[&str],
+) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let version: Version = find_version(db, version)?;
+        let path = &paths::portable(path.trim_end_matches('/'));
+        if !Path::new(&paths::expand(path)?).is_dir() {
+            return Err(Error::Usage(format!("{} is not a directory", path)));
+        }
+        let tracked: Vec<TrackedDir> =
+            TrackedDir::select_where(db, "path=?1 AND version_id=?2", params![path, version.id])?;
+        if !tracked.is_empty() {
+            return Err(Error::Conflict(format!(
+                "directory {} is already tracked in version {}",
+                path, version.name
+            )));
+        }
+        let tracked_dir = TrackedDir {
+            id: TrackedDir::next_id(db)?,
+            version_id: version.id,
+            path: path.to_string(),
+            include: include.join("\n"),
+            exclude: exclude.join("\n"),
+        };
+        tracked_dir.create(db)?;
+        let added = add_untracked_files(ctx, db, &tracked_dir)?;
+        done(
+            ctx,
+            format_args!(
+                "Directory {} tracked, {} config record(s) in database created",
+                path,
+                added.len()
+            ),
+        );
+        Ok(())
+    })
+}
 
-    let new_config = Config {
-        id: new_id,
-        version_id: versions[0].id,
-        path: path.to_string(),
-        data: file_lines
-            .split("\n")
-            .map(|e| e.to_string())
-            .collect::<Vec<String>>(),
-    };
-    new_config
-        .create(&db)
-        .expect("could not create config in db");
-    println!("Config record in database created");
+/// rewrites absolute paths of configs and tracked directories under home
+/// of this machine into portable form (~/..., $XDG_CONFIG_HOME/...)
+pub fn rewrite_paths(ctx: &Context) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let mut rewritten: Vec<(String, String)> = vec![];
+        for config in fetch_configs(db)? {
+            let portable = paths::portable(&config.path);
+            if portable != config.path {
+                Config::update(db, config.id, "path", &portable)?;
+                rewritten.push((config.path, portable));
+            }
+        }
+        for tracked_dir in TrackedDir::all(db)? {
+            let portable = paths::portable(&tracked_dir.path);
+            if portable != tracked_dir.path {
+                TrackedDir::update(db, tracked_dir.id, "path", &portable)?;
+                rewritten.push((tracked_dir.path, portable));
+            }
+        }
+        if rewritten.is_empty() {
+            println!("All paths are portable");
+            return Ok(());
+        }
+        println!(
+            "{}",
+            if ctx.dry_run {
+                "Paths to rewrite:"
+            } else {
+                "Rewritten paths:"
+            }
+        );
+        for (i, (old, new)) in rewritten.iter().enumerate() {
+            let item = format!("{} => {}", old, new);
+            println!("{}", tree_item(i, rewritten.len(), 0, &item));
+        }
+        Ok(())
+    })
+}
+
+/// binds this machine (or given hostname) to default version,
+/// without version the binding is removed
+pub fn bind(ctx: &Context, version: Option<&str>, hostname: Option<&str>) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let hostname = hostname.map_or_else(current_hostname, str::to_string);
+        Machine::delete(db, "hostname=?1", params![hostname])?;
+        match version {
+            Some(version) => {
+                let version: Version = find_version(db, version)?;
+                Machine {
+                    id: Machine::next_id(db)?,
+                    hostname: hostname.clone(),
+                    version_id: version.id,
+                }
+                .create(db)?;
+                done(
+                    ctx,
+                    format_args!("Machine {} bound to version {}", hostname, version.name),
+                );
+            }
+            None => done(ctx, format_args!("Machine {} unbound", hostname)),
+        }
+        Ok(())
+    })
+}
+
+/// prints hostname of this machine and its default version
+pub fn whoami(ctx: &Context) -> Result<()> {
+    let db = get_db(ctx)?;
+    println!("hostname: {}", current_hostname());
+    match default_version(&db)? {
+        Some((version, source)) => println!("version: {} ({})", version, source),
+        None => println!("version: none (all versions, bind one with bind -v)"),
+    }
     Ok(())
 }
 
 /// adds new version to database
-pub fn add_version(db: &str, name: &str) -> std::io::Result<()> {
-    let db = get_db(db);
-    let new_id: i32 = Version::next_id(&db).expect("could not fetch next id");
+/// with parent the version is layered on it (inherits its configs)
+pub fn add_version(ctx: &Context, name: &str, parent: Option<&str>) -> Result<()> {
+    in_transaction(ctx, |db| {
+        if !Version::select_where(db, "name=?1", params![name])?.is_empty() {
+            return Err(Error::Conflict(format!("version {} already exists", name)));
+        }
+        let new_id: i32 = Version::next_id(db)?;
+        let parent_id: Option<i32> = match parent {
+            Some(parent) => Some(find_version(db, parent)?.id),
+            None => None,
+        };
 
-    let new_version = Version {
-        id: new_id,
-        name: name.to_string(),
-    };
-    new_version
-        .create(&db)
-        .expect("could not create version in db");
-    println!("Version record in database created");
-    Ok(())
+        let new_version = Version {
+            id: new_id,
+            name: name.to_string(),
+            parent_id,
+        };
+        new_version.create(db)?;
+        done(ctx, format_args!("Version record in database created"));
+        Ok(())
+    })
 }
 
 /// db => real files
-/// Writes into files from database
-pub fn write_all(db: &str) -> std::io::Result<()> {
-    let db = get_db(db);
-    let configs: Vec<Config> = fetch_configs(&db).expect("could not fetch data");
-    println!("db => real file contents:");
-    for config in configs {
-        println!("{}", config.path);
-        let mut file = File::create(config.path)?;
-        for line in config.data {
-            file.write_all(format!("{}\n", line).as_bytes())?;
+/// Writes into files from database, restoring their mode, mtime
+/// and (if owner is set) ownership
+pub fn write_all(ctx: &Context, selector: &Selector, owner: bool) -> Result<()> {
+    in_transaction_keeping_writes(ctx, |db| {
+        let configs: Vec<Config> = select_configs(db, selector)?;
+        let scope = template_scope(db, selector)?;
+        let batch = next_batch(db)?;
+        // everything is rendered (and decrypted) before first file is written
+        let mut planned: Vec<(Config, Vec<u8>)> = vec![];
+        for config in configs {
+            let data = rendered(ctx, db, &config, &config.data, scope)?;
+            planned.push((config, data));
+        }
+        println!("db => real file contents:");
+        for (config, data) in planned {
+            println!("{}", config.path);
+            write_backed_up(ctx, db, batch, &config, &data)?;
+            restore_metadata(ctx, &config, owner)?;
+            restore_mtime(ctx, &config)?;
+            set_base(db, config.id, &config.data)?;
+        }
+        prune_backups(db)?;
+        done(ctx, format_args!("All files written to your system tree"));
+        Ok(())
+    })
+}
+
+/// restores files overwritten by the last write batch on this machine
+/// (write, sync, ...), repeated undo goes further back
+pub fn undo(ctx: &Context) -> Result<()> {
+    in_transaction_keeping_writes(ctx, |db| undo_last_batch(ctx, db))
+}
+
+/// restores files (and their sync bases) backed up by last write batch of this machine
+fn undo_last_batch(ctx: &Context, db: &Transaction) -> Result<()> {
+    let hostname = current_hostname();
+    let last: Vec<Backup> = Backup::select_where(
+        db,
+        "hostname=?1 ORDER BY batch DESC LIMIT 1",
+        params![hostname],
+    )?;
+    let batch = match last.first() {
+        Some(backup) => backup.batch,
+        None => return Err(Error::NotFound("write batch to undo".to_string())),
+    };
+    let backups: Vec<Backup> = Backup::select_where(
+        db,
+        "hostname=?1 AND batch=?2 ORDER BY id DESC",
+        params![hostname, batch],
+    )?;
+    // files edited after the write would lose the edit, nothing is restored then
+    // (only last write of path in batch is on disk)
+    let mut changed: Vec<&str> = vec![];
+    let mut checked: HashSet<&str> = HashSet::new();
+    for backup in backups.iter() {
+        if !checked.insert(&backup.path) {
+            continue;
+        }
+        if let Some(written_hash) = &backup.written_hash {
+            let location = paths::expand(&backup.path)?;
+            match read_disk(&location, backup.kind) {
+                Ok(current) if content_hash(&current) != *written_hash => {
+                    changed.push(&backup.path)
+                }
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(Error::io(&location, e)),
+                _ => {}
+            }
+        }
+    }
+    if !changed.is_empty() {
+        return Err(Error::Conflict(format!(
+            "files changed since the write, move them away or read them into db first: {}",
+            changed.join(", ")
+        )));
+    }
+    println!(
+        "Undoing write batch from {}:",
+        format_timestamp(backups[0].created_at)
+    );
+    for backup in backups.iter() {
+        let config = Config {
+            path: backup.path.clone(),
+            kind: backup.kind,
+            encrypted: backup.encrypted,
+            ..Config::default()
+        };
+        match &backup.data {
+            Some(data) => {
+                write_config(ctx, &config, &decrypted(ctx, &config, data)?)?;
+                done(ctx, format_args!("{} restored", backup.path));
+            }
+            None => {
+                let location = paths::expand(&backup.path)?;
+                if ctx.dry_run {
+                    println!("dry run: remove {}", location);
+                } else {
+                    match fs::remove_file(&location) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => {
+                            return Err(Error::io(&location, e))
+                        }
+                        _ => println!("{} removed (did not exist before)", backup.path),
+                    }
+                }
+            }
+        }
+        // sync base goes back too, so restored file is not taken for local edit
+        if let Some(config_id) = backup.config_id {
+            if !Config::select_where(db, "id=?1", params![config_id])?.is_empty() {
+                set_base_hash(db, config_id, backup.base_hash.clone())?;
+            }
         }
+        // backup is dropped as soon as its file is restored (kept when undo fails later)
+        Backup::delete(db, "id=?1", params![backup.id])?;
     }
-    println!("All files written to your system tree");
     Ok(())
 }
 
 /// real files => db
 /// Reads actual file contents and updates their data in database
-pub fn read_all(db: &str) -> Result<()> {
-    let db = get_db(db);
-    let configs: Vec<Config> = fetch_configs(&db).expect("could not fetch data");
+/// (only configs matching selector, new files are picked up from tracked
+/// directories of selected version)
+pub fn read_all(ctx: &Context, selector: &Selector) -> Result<()> {
+    in_transaction(ctx, |db| read_configs(ctx, db, selector))
+}
+
+/// reads selected configs (and new files of their tracked directories) into db,
+/// files vanished from tracked directories are reported and skipped
+fn read_configs(ctx: &Context, db: &Transaction, selector: &Selector) -> Result<()> {
+    let tracked_dirs: Vec<TrackedDir> = match selected_version(db, selector)? {
+        Some(version) => TrackedDir::select_where(
+            db,
+            &format!(
+                "version_id IN ({})",
+                chain_ids(&version_chain(db, &version)?)
+            ),
+            &[],
+        )?,
+        None => TrackedDir::all(db)?,
+    };
+    for tracked_dir in tracked_dirs.iter() {
+        for path in add_untracked_files(ctx, db, tracked_dir)? {
+            println!("New config in tracked directory: {}", path);
+        }
+    }
+    let configs: Vec<Config> = select_configs(db, selector)?;
+    let scope = template_scope(db, selector)?;
+    let mut refused: Vec<String> = vec![];
     println!("Real file data => db:");
-    for config in configs {
-        let new_data = fs::read_to_string(config.path).expect("could not read file in db");
-        let encoded = encode(new_data);
-        Config::update(&db, config.id, "data", &encoded)?
+    for mut config in configs {
+        let disk = match read_config(&config) {
+            Ok(disk) => disk,
+            Err(Error::Io(_, e))
+                if e.kind() == ErrorKind::NotFound
+                    && tracked_dirs.iter().any(|tracked_dir| {
+                        tracked_dir.version_id == config.version_id
+                            && config.path.starts_with(&format!("{}/", tracked_dir.path))
+                    }) =>
+            {
+                println!("{} is missing from tracked directory, skipped", config.path);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let new_data = match unrendered(ctx, db, &config, &disk, scope)? {
+            Some(new_data) => new_data,
+            None => {
+                refused.push(config.path);
+                continue;
+            }
+        };
+        snapshot(db, config.id, &new_data)?;
+        set_base(db, config.id, &new_data)?;
+        Config::update(db, config.id, "data", &new_data)?;
+        record_metadata(&mut config)?;
+        store_metadata(db, &config)?;
+    }
+    if !refused.is_empty() {
+        return Err(Error::Conflict(format!(
+            "nothing was read, templates edited in lines with placeholders: {}",
+            refused.join(", ")
+        )));
     }
-    println!("All config contents refreshed in db");
+    done(ctx, format_args!("All config contents refreshed in db"));
     Ok(())
 }
 
-/// lists line separated list of versions stored in db
-pub fn list_versions(db: &str) -> Result<()> {
-    let db = get_db(db);
-    let versions: Vec<Version> = Version::all(&db)?;
-    for version in versions {
-        println!("{}. {}", version.id, version.name);
+/// db revision => real file
+/// Writes data of one specific revision into file of its config
+pub fn write_revision(ctx: &Context, id: i32, owner: bool) -> Result<()> {
+    in_transaction_keeping_writes(ctx, |db| {
+        let revision: Revision =
+            Revision::find(db, id).map_err(|_| Error::NotFound(format!("revision {}", id)))?;
+        let config: Config = Config::find(db, revision.config_id)?;
+        let data = rendered(ctx, db, &config, &revision.data, None)?;
+        write_backed_up(ctx, db, next_batch(db)?, &config, &data)?;
+        prune_backups(db)?;
+        restore_metadata(ctx, &config, owner)?;
+        done(
+            ctx,
+            format_args!("{} restored to revision {}", config.path, revision.id),
+        );
+        Ok(())
+    })
+}
+
+/// db revisions => real files
+/// Writes every config as it was stored at given date
+/// (date-only values mean the end of that day)
+pub fn write_at(ctx: &Context, selector: &Selector, date: &str, owner: bool) -> Result<()> {
+    in_transaction_keeping_writes(ctx, |db| {
+        let timestamp = parse_date(date)?;
+        let configs: Vec<Config> = select_configs(db, selector)?;
+        let scope = template_scope(db, selector)?;
+        let batch = next_batch(db)?;
+        // everything is rendered (and decrypted) before first file is written
+        // (data of revision is replaced by rendered one)
+        let mut planned: Vec<(Config, Option<Revision>)> = vec![];
+        for config in configs {
+            let revisions: Vec<Revision> = Revision::select_where(
+                db,
+                "config_id=?1 AND created_at<=?2 ORDER BY created_at DESC, id DESC LIMIT 1",
+                params![config.id, timestamp],
+            )?;
+            let revision = match revisions.into_iter().next() {
+                Some(mut revision) => {
+                    revision.data = rendered(ctx, db, &config, &revision.data, scope)?;
+                    Some(revision)
+                }
+                None => None,
+            };
+            planned.push((config, revision));
+        }
+        println!(
+            "db revisions at {} => real file contents:",
+            format_timestamp(timestamp)
+        );
+        for (config, revision) in planned {
+            match revision {
+                Some(revision) => {
+                    println!("{} (revision {})", config.path, revision.id);
+                    write_backed_up(ctx, db, batch, &config, &revision.data)?;
+                    restore_metadata(ctx, &config, owner)?;
+                }
+                None => println!("{} (no revision at that time, skipped)", config.path),
+            }
+        }
+        prune_backups(db)?;
+        done(ctx, format_args!("All files written to your system tree"));
+        Ok(())
+    })
+}
+
+/// lists stored revisions of config(s) with given path
+pub fn history(ctx: &Context, path: &str, format: Format) -> Result<()> {
+    let db = get_db(ctx)?;
+    let path = &paths::portable(path);
+    let configs: Vec<Config> = Config::select_where(&db, "path=?1", params![path])?;
+    if configs.is_empty() {
+        return Err(Error::NotFound(format!("config {}", path)));
+    }
+    if format != Format::Tree {
+        let mut records: Vec<output::RevisionRecord> = vec![];
+        for config in configs {
+            let version_name = Version::find(&db, config.version_id)?.name;
+            for revision in
+                Revision::select_where(&db, "config_id=?1 ORDER BY id", params![config.id])?
+            {
+                records.push(output::RevisionRecord {
+                    id: revision.id,
+                    config_id: config.id,
+                    version: version_name.clone(),
+                    path: config.path.clone(),
+                    size: revision.data.len(),
+                    hash: revision.hash,
+                    hostname: revision.hostname,
+                    created_at: revision.created_at,
+                });
+            }
+        }
+        return output::print_records(format, &records, |record| {
+            format!("{} {}", record.id, record.hash)
+        });
+    }
+    for config in configs {
+        let version_name = Version::find(&db, config.version_id)?.name;
+        let revisions: Vec<Revision> =
+            Revision::select_where(&db, "config_id=?1 ORDER BY id", params![config.id])?;
+        let header = format!("{} ({})", config.path, version_name);
+        println!("{}", header);
+        for (i, revision) in revisions.iter().enumerate() {
+            let item = format!(
+                "{}. {} {} {} ({} bytes)",
+                revision.id,
+                format_timestamp(revision.created_at),
+                revision.hostname,
+                &revision.hash[..12],
+                revision.data.len()
+            );
+            println!("{}", tree_item(i, revisions.len(), header.len() + 1, &item));
+        }
     }
     Ok(())
 }
 
-/// list version and its configs
-pub fn list_version(db: &str, name: &str) -> Result<()> {
-    let db = get_db(db);
-    let versions: Vec<Version> =
-        Version::select_where(&db, &format!("name='{}'", name)).expect("could not select version");
-    if versions.len() == 0 {
-        panic!("No version matches criteria");
+/// shows unified diff between real files (old) and data stored in db (new)
+/// returns true if any of selected configs differs
+pub fn diff(ctx: &Context, selector: &Selector) -> Result<bool> {
+    let db = get_db(ctx)?;
+    let configs: Vec<Config> = select_configs(&db, selector)?;
+    let scope = template_scope(&db, selector)?;
+    let color = std::io::stdout().is_terminal();
+    let mut differs = false;
+    for config in configs {
+        let data = rendered(ctx, &db, &config, &config.data, scope)?;
+        let location = paths::expand(&config.path)?;
+        let disk: Option<Vec<u8>> = match read_disk(&location, config.kind) {
+            Ok(data) => Some(data),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::io(&location, e)),
+        };
+        if disk.as_ref() == Some(&data) {
+            continue;
+        }
+        differs = true;
+        for line in config_diff(&config, disk.as_deref(), &data) {
+            println!("{}", colorize_diff_line(&line, color));
+        }
+    }
+    Ok(differs)
+}
+
+/// lines of unified diff of config between file on disk (a, None when missing)
+/// and contents stored in db (b), secrets and binary files only reported as differing
+fn config_diff(config: &Config, disk: Option<&[u8]>, data: &[u8]) -> Vec<String> {
+    let path = config.path.trim_start_matches('/');
+    let old_header = match disk {
+        Some(_) => format!("a/{}", path),
+        None => "/dev/null".to_string(),
+    };
+    let new_header = format!("b/{}", path);
+    let disk = disk.unwrap_or_default();
+    if config.encrypted {
+        // contents of secrets are never printed
+        return vec![format!(
+            "Encrypted files {} and {} differ",
+            old_header, new_header
+        )];
     }
-    let version: &Version = &versions[0];
-    let configs: Vec<Config> = Config::select_where(&db, &format!("version_id='{}'", version.id))
-        .expect("could not select configs by version id");
+    if is_binary(disk) || is_binary(data) {
+        return vec![format!(
+            "Binary files {} and {} differ",
+            old_header, new_header
+        )];
+    }
+    TextDiff::from_lines(
+        String::from_utf8_lossy(disk).as_ref(),
+        String::from_utf8_lossy(data).as_ref(),
+    )
+    .unified_diff()
+    .header(&old_header, &new_header)
+    .to_string()
+    .lines()
+    .map(str::to_string)
+    .collect()
+}
 
-    println!("{}", version.name);
-    for (i, config) in configs.iter().enumerate() {
-        println!(
-            "{}",
-            tree_item(i, configs.len(), version.name.len() + 1, &config.path)
-        );
+/// wraps line of unified diff into ansi color codes based on its prefix
+fn colorize_diff_line(line: &str, color: bool) -> String {
+    let code = if line.starts_with("+++") || line.starts_with("---") {
+        "1"
+    } else if line.starts_with("@@") {
+        "36"
+    } else if line.starts_with('+') {
+        "32"
+    } else if line.starts_with('-') {
+        "31"
+    } else {
+        return line.to_string();
+    };
+    match color {
+        true => format!("\x1b[{}m{}\x1b[0m", code, line),
+        false => line.to_string(),
     }
+}
 
-    Ok(())
+/// data is considered binary if it is not utf8 or contains null byte
+fn is_binary(data: &[u8]) -> bool {
+    data.contains(&0) || std::str::from_utf8(data).is_err()
 }
 
-/// prints line of tree list
-fn tree_item(index: usize, total_len: usize, shift_len: usize, item: &str) -> String {
-    let mut tree_branch = "├──";
-    if (index == 0 && total_len == 1) || index == total_len - 1 {
-        tree_branch = "└──";
+/// state of config on disk compared to db and to last sync on this machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// file on disk matches db
+    Unchanged,
+    /// file changed since last sync, db did not
+    ModifiedOnDisk,
+    /// db changed since last sync, file did not
+    ModifiedInDb,
+    /// both changed since last sync (or it never happened on this machine)
+    Diverged,
+    /// file does not exist
+    MissingOnDisk,
+    /// file exists but can not be read
+    Unreadable,
+}
+
+impl Status {
+    /// short two letter code, first letter for db side, second for disk side
+    pub fn code(&self) -> &'static str {
+        match self {
+            Status::Unchanged => "  ",
+            Status::ModifiedOnDisk => " M",
+            Status::ModifiedInDb => "M ",
+            Status::Diverged => "MM",
+            Status::MissingOnDisk => " D",
+            Status::Unreadable => "!!",
+        }
+    }
+
+    /// stable name used by machine readable formats
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Unchanged => "unchanged",
+            Status::ModifiedOnDisk => "modified-on-disk",
+            Status::ModifiedInDb => "modified-in-db",
+            Status::Diverged => "diverged",
+            Status::MissingOnDisk => "missing-on-disk",
+            Status::Unreadable => "unreadable",
+        }
+    }
+
+    /// human readable description
+    pub fn description(&self) -> &'static str {
+        match self {
+            Status::Unchanged => "unchanged",
+            Status::ModifiedOnDisk => "modified on disk",
+            Status::ModifiedInDb => "modified in db",
+            Status::Diverged => "modified on disk and in db",
+            Status::MissingOnDisk => "missing on disk",
+            Status::Unreadable => "unreadable",
+        }
     }
-    format!("{}{} {}", " ".repeat(shift_len), tree_branch, item)
 }
 
-/// lists line separated list of configs stored in db
-pub fn list_configs(db: &str) -> Result<()> {
-    let db = get_db(db);
-    let configs: Vec<Config> = fetch_configs(&db).expect("could not fetch data");
-    if configs.len() == 0 {
-        println!("No configs in db");
-    } else {
-        let mut map: HashMap<String, Vec<String>> = HashMap::new();
+/// classifies config by comparing file on disk, db data and last synced base
+/// (templates are compared rendered with variables of scope)
+fn config_status(
+    ctx: &Context,
+    db: &Connection,
+    config: &Config,
+    scope: Option<i32>,
+) -> Result<Status> {
+    let disk: Vec<u8> = match read_disk(&paths::expand(&config.path)?, config.kind) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Status::MissingOnDisk),
+        Err(_) => return Ok(Status::Unreadable),
+    };
+    if disk == rendered(ctx, db, config, &config.data, scope)? {
+        return Ok(Status::Unchanged);
+    }
+    let base = match base_hash(db, config.id)? {
+        Some(base) => base,
+        None => return Ok(Status::Diverged),
+    };
+    if base == content_hash(&config.data) {
+        return Ok(Status::ModifiedOnDisk);
+    }
+    let disk_is_base = match config.template || config.encrypted {
+        false => base == content_hash(&disk),
+        true => {
+            Revision::select_where(
+                db,
+                "config_id=?1 AND hash=?2 ORDER BY id DESC LIMIT 1",
+                params![config.id, base],
+            )?
+            .first()
+            .map(|revision| rendered(ctx, db, config, &revision.data, scope))
+            .transpose()?
+                == Some(disk)
+        }
+    };
+    match disk_is_base {
+        true => Ok(Status::ModifiedInDb),
+        false => Ok(Status::Diverged),
+    }
+}
+
+/// lists configs (optionally of one version) with their drift status
+/// porcelain output prints one tab separated line per config: code, version, path
+pub fn status(ctx: &Context, selector: &Selector, porcelain: bool, format: Format) -> Result<()> {
+    let db = get_db(ctx)?;
+    let configs: Vec<Config> = select_configs(&db, selector)?;
+    let scope = template_scope(&db, selector)?;
+    if format != Format::Tree {
+        let mut records: Vec<output::StatusRecord> = vec![];
         for config in configs {
-            let version_name = Version::find(&db, config.version_id)?.name;
-            if let Some(version_vec) = map.get_mut(&version_name) {
-                version_vec.push(config.path);
-            } else {
-                map.insert(version_name, vec![config.path]);
-            }
+            let status = config_status(ctx, &db, &config, scope)?;
+            records.push(output::StatusRecord {
+                id: config.id,
+                version: Version::find(&db, config.version_id)?.name,
+                path: config.path,
+                status: status.name(),
+                code: status.code(),
+            });
         }
-        for (version_name, config_vec) in map {
-            println!("================");
+        return output::print_records(format, &records, |record| {
+            format!("{} {}", record.status, record.path)
+        });
+    }
+    if configs.is_empty() && !porcelain {
+        println!("No configs in db");
+    }
+    for (version_name, config_vec) in group_by_version(&db, configs)? {
+        if !porcelain {
             println!("{}", version_name);
-            for (i, config_path) in config_vec.iter().enumerate() {
+        }
+        for (i, config) in config_vec.iter().enumerate() {
+            let status = config_status(ctx, &db, config, scope)?;
+            if porcelain {
+                println!("{}\t{}\t{}", status.code(), version_name, config.path);
+            } else {
+                let item = format!("{:<27} {}", status.description(), config.path);
                 println!(
                     "{}",
-                    tree_item(i, config_vec.len(), version_name.len() + 1, config_path)
+                    tree_item(i, config_vec.len(), version_name.len() + 1, &item)
                 );
             }
         }
@@ -278,79 +1198,2597 @@ pub fn list_configs(db: &str) -> Result<()> {
     Ok(())
 }
 
-/// gets all the configs as a Vec<Config>
-fn fetch_configs(db: &Connection) -> Result<Vec<Config>> {
-    let configs: Vec<Config> = Config::all(&db)?;
-    Ok(configs)
+/// real files <=> db
+/// Brings files and db in sync using base from last sync on this machine,
+/// side that changed wins, if both changed they are merged line by line.
+/// Conflicting configs are only listed, unless markers is set,
+/// then file is written with conflict markers to be resolved and read back.
+/// returns true if there were any conflicts
+pub fn sync(ctx: &Context, selector: &Selector, markers: bool, owner: bool) -> Result<bool> {
+    in_transaction_keeping_writes(ctx, |db| {
+        let configs: Vec<Config> = select_configs(db, selector)?;
+        let scope = template_scope(db, selector)?;
+        let batch = next_batch(db)?;
+        let mut conflicted: Vec<String> = vec![];
+        println!("Real files <=> db:");
+        // everything is rendered (and decrypted) before first file is written,
+        // data is the one to write or (for files modified on disk) to store
+        let mut planned: Vec<(Config, Status, Vec<u8>)> = vec![];
+        for config in configs {
+            let status = config_status(ctx, db, &config, scope)?;
+            let data = match status {
+                Status::ModifiedOnDisk => {
+                    let disk = read_config(&config)?;
+                    match unrendered(ctx, db, &config, &disk, scope)? {
+                        Some(new_data) => new_data,
+                        None => {
+                            println!("{} template edited in lines with placeholders", config.path);
+                            conflicted.push(config.path);
+                            continue;
+                        }
+                    }
+                }
+                Status::ModifiedInDb | Status::MissingOnDisk => {
+                    rendered(ctx, db, &config, &config.data, scope)?
+                }
+                _ => vec![],
+            };
+            planned.push((config, status, data));
+        }
+        for (mut config, status, data) in planned {
+            match status {
+                Status::Unchanged => set_base(db, config.id, &config.data)?,
+                Status::ModifiedOnDisk => {
+                    snapshot(db, config.id, &data)?;
+                    set_base(db, config.id, &data)?;
+                    Config::update(db, config.id, "data", &data)?;
+                    record_metadata(&mut config)?;
+                    store_metadata(db, &config)?;
+                    println!("{} => db", config.path);
+                }
+                Status::ModifiedInDb | Status::MissingOnDisk => {
+                    write_backed_up(ctx, db, batch, &config, &data)?;
+                    restore_metadata(ctx, &config, owner)?;
+                    restore_mtime(ctx, &config)?;
+                    set_base(db, config.id, &config.data)?;
+                    println!("db => {}", config.path);
+                }
+                Status::Unreadable => println!("{} is unreadable, skipped", config.path),
+                Status::Diverged => {
+                    let disk = read_config(&config)?;
+                    let base: Option<Revision> = match base_hash(db, config.id)? {
+                        Some(hash) => Revision::select_where(
+                            db,
+                            "config_id=?1 AND hash=?2 ORDER BY id DESC LIMIT 1",
+                            params![config.id, hash],
+                        )?
+                        .into_iter()
+                        .next(),
+                        None => None,
+                    };
+                    let texts = (
+                        base.as_ref()
+                            .map(|revision| std::str::from_utf8(&revision.data)),
+                        std::str::from_utf8(&disk),
+                        std::str::from_utf8(&config.data),
+                    );
+                    let merged = match texts {
+                        (Some(Ok(base)), Ok(ours), Ok(theirs))
+                            if config.kind == ConfigKind::File
+                                && !config.template
+                                && !config.encrypted =>
+                        {
+                            merge::merge(base, ours, theirs)
+                        }
+                        _ => {
+                            println!(
+                            "{} can not be merged (no base, binary, symlink, template or secret)",
+                            config.path
+                        );
+                            conflicted.push(config.path);
+                            continue;
+                        }
+                    };
+                    let merged_data = merged.text.into_bytes();
+                    if merged.conflicts == 0 {
+                        write_backed_up(ctx, db, batch, &config, &merged_data)?;
+                        snapshot(db, config.id, &merged_data)?;
+                        set_base(db, config.id, &merged_data)?;
+                        Config::update(db, config.id, "data", &merged_data)?;
+                        println!("{} <=> db merged", config.path);
+                        continue;
+                    }
+                    if markers {
+                        write_backed_up(ctx, db, batch, &config, &merged_data)?;
+                        println!(
+                            "{} has {} conflict(s), markers written to file",
+                            config.path, merged.conflicts
+                        );
+                    } else {
+                        println!("{} has {} conflict(s)", config.path, merged.conflicts);
+                    }
+                    conflicted.push(config.path);
+                }
+            }
+        }
+        prune_backups(db)?;
+        if conflicted.is_empty() {
+            done(ctx, format_args!("All configs in sync"));
+        } else {
+            println!("Conflicting configs (left untouched in db):");
+            for (i, path) in conflicted.iter().enumerate() {
+                println!("{}", tree_item(i, conflicted.len(), 0, path));
+            }
+            if markers {
+                println!("Resolve conflict markers in files and store them with read");
+            }
+        }
+        Ok(!conflicted.is_empty())
+    })
 }
 
-/// testing version entity, create and fetch
-#[test]
-fn version_entity() -> Result<()> {
-    let db = get_db(DEFAULT_DB_LOCATION);
-
-    // setup
-    Version::table(&db)?;
-    let test_version = Version {
-        id: 1,
-        name: "home".to_string(),
-    };
-    test_version.create(&db)?;
-
-    // all
+/// lists line separated list of versions stored in db
+pub fn list_versions(ctx: &Context, format: Format) -> Result<()> {
+    let db = get_db(ctx)?;
     let versions: Vec<Version> = Version::all(&db)?;
-    assert_eq!(1, versions[0].id);
-    assert_eq!("home", versions[0].name);
-
-    // find
-    let version: Version = Version::find(&db, 1)?;
-    assert_eq!(1, version.id);
-    assert_eq!("home".to_string(), version.name);
-
-    // update
-    Version::update(&db, version.id, "name", "work")?;
-    let updated_version: Version = Version::find(&db, 1)?;
-    assert_eq!(1, updated_version.id);
-    assert_eq!("work".to_string(), updated_version.name);
-
+    if format != Format::Tree {
+        let mut records: Vec<output::VersionRecord> = vec![];
+        for version in versions.iter() {
+            records.push(output::VersionRecord {
+                id: version.id,
+                name: version.name.clone(),
+                parent: versions
+                    .iter()
+                    .find(|parent| Some(parent.id) == version.parent_id)
+                    .map(|parent| parent.name.clone()),
+                configs: Config::select_where(&db, "version_id=?1", params![version.id])?.len(),
+            });
+        }
+        return output::print_records(format, &records, |record| record.name.clone());
+    }
+    for version in versions.iter() {
+        let parent = versions
+            .iter()
+            .find(|parent| Some(parent.id) == version.parent_id);
+        match parent {
+            Some(parent) => println!(
+                "{}. {} (layered on {})",
+                version.id, version.name, parent.name
+            ),
+            None => println!("{}. {}", version.id, version.name),
+        }
+    }
     Ok(())
 }
 
-/// testing config entity, create and fetch
-#[test]
+/// sets new variable of version used to render its templates
+pub fn add_variable(ctx: &Context, version: &str, assignment: &str) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let version: Version = find_version(db, version)?;
+        let (name, value) = parse_assignment(assignment)?;
+        if !Variable::select_where(db, "version_id=?1 AND name=?2", params![version.id, name])?
+            .is_empty()
+        {
+            return Err(Error::Conflict(format!(
+                "variable {} of version {} already exists, change it by update",
+                name, version.name
+            )));
+        }
+        Variable {
+            id: Variable::next_id(db)?,
+            version_id: version.id,
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+        .create(db)?;
+        done(ctx, format_args!("Variable record in database created"));
+        Ok(())
+    })
+}
+
+/// changes value of variable of version
+pub fn update_variable(ctx: &Context, version: &str, assignment: &str) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let version: Version = find_version(db, version)?;
+        let (name, value) = parse_assignment(assignment)?;
+        let variable: Variable =
+            Variable::select_where(db, "version_id=?1 AND name=?2", params![version.id, name])?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    Error::NotFound(format!("variable {} of version {}", name, version.name))
+                })?;
+        Variable::update(db, variable.id, "value", &value)?;
+        done(ctx, format_args!("Variable {} update successfull", name));
+        Ok(())
+    })
+}
+
+/// removes variable of version
+pub fn delete_variable(ctx: &Context, version: &str, name: &str) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let version: Version = find_version(db, version)?;
+        if Variable::select_where(db, "version_id=?1 AND name=?2", params![version.id, name])?
+            .is_empty()
+        {
+            return Err(Error::NotFound(format!(
+                "variable {} of version {}",
+                name, version.name
+            )));
+        }
+        Variable::delete(db, "version_id=?1 AND name=?2", params![version.id, name])?;
+        Ok(())
+    })
+}
+
+/// lists variables templates of version are rendered with (inherited ones are marked)
+pub fn list_variables(ctx: &Context, version: &str, format: Format) -> Result<()> {
+    let db = get_db(ctx)?;
+    let chain: Vec<Version> = version_chain(&db, version)?;
+    let layer = |variable: &Variable| {
+        chain
+            .iter()
+            .position(|layer| layer.id == variable.version_id)
+    };
+    let mut variables: Vec<Variable> =
+        Variable::select_where(&db, &format!("version_id IN ({})", chain_ids(&chain)), &[])?;
+    variables.sort_by_key(layer);
+    let mut seen: HashSet<String> = HashSet::new();
+    variables.retain(|variable| seen.insert(variable.name.clone()));
+    variables.sort_by(|a, b| a.name.cmp(&b.name));
+    if format != Format::Tree {
+        let records: Vec<output::VariableRecord> = variables
+            .into_iter()
+            .map(|variable| output::VariableRecord {
+                id: variable.id,
+                version: layer(&variable).map_or(String::new(), |index| chain[index].name.clone()),
+                name: variable.name,
+                value: variable.value,
+            })
+            .collect();
+        return output::print_records(format, &records, |record| {
+            format!("{}={}", record.name, record.value)
+        });
+    }
+    for variable in variables {
+        let origin = match layer(&variable) {
+            Some(index) if index > 0 => format!(" (from {})", chain[index].name),
+            _ => String::new(),
+        };
+        println!("{}={}{}", variable.name, variable.value, origin);
+    }
+    Ok(())
+}
+
+/// splits NAME=VALUE argument, names are letters, digits and underscores
+fn parse_assignment(assignment: &str) -> Result<(&str, &str)> {
+    match assignment.split_once('=') {
+        Some((name, value))
+            if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok((name, value))
+        }
+        _ => Err(Error::Usage(format!(
+            "variable has to be in format NAME=VALUE (name of letters, digits and _), not {}",
+            assignment
+        ))),
+    }
+}
+
+/// list version and its effective configs (inherited ones are marked by their version)
+pub fn list_version(ctx: &Context, name: &str, format: Format) -> Result<()> {
+    let db = get_db(ctx)?;
+    let (chain, configs) = version_configs(&db, name)?;
+    if format != Format::Tree {
+        return print_configs(&db, &configs, format);
+    }
+    let version: &Version = &chain[0];
+    let tracked_dirs: Vec<TrackedDir> =
+        TrackedDir::select_where(&db, &format!("version_id IN ({})", chain_ids(&chain)), &[])?;
+    let origin = |version_id: i32| -> String {
+        match chain.iter().find(|layer| layer.id == version_id) {
+            Some(layer) if layer.id != version.id => format!(" (from {})", layer.name),
+            _ => String::new(),
+        }
+    };
+    let mut items: Vec<String> = tracked_dirs
+        .iter()
+        .map(|tracked_dir| {
+            format!(
+                "{}/ (tracked directory){}",
+                tracked_dir.path,
+                origin(tracked_dir.version_id)
+            )
+        })
+        .collect();
+    items.extend(
+        configs
+            .iter()
+            .map(|config| format!("{}{}", config_label(config), origin(config.version_id))),
+    );
+
+    println!("{}", version.name);
+    for (i, item) in items.iter().enumerate() {
+        println!(
+            "{}",
+            tree_item(i, items.len(), version.name.len() + 1, item)
+        );
+    }
+
+    Ok(())
+}
+
+/// exports configs of version (inherited ones included, templates rendered) into new
+/// directory tree or tarball with manifest, encrypted configs are skipped unless
+/// decrypt_secrets is set (tree is then readable only by owner)
+pub fn export(ctx: &Context, name: &str, target: &str, decrypt_secrets: bool) -> Result<()> {
+    let db = get_db(ctx)?;
+    let (chain, configs) = version_configs(&db, name)?;
+    let version: &Version = &chain[0];
+    let mut entries: Vec<archive::ManifestEntry> = vec![];
+    let mut files: Vec<archive::ExportedFile> = vec![];
+    let mut secrets = false;
+    for config in configs {
+        if config.encrypted && !decrypt_secrets {
+            println!(
+                "{} is encrypted, skipped (export it with --decrypt-secrets)",
+                config.path
+            );
+            continue;
+        }
+        secrets |= config.encrypted;
+        let file = archive::tree_path(&config.path)?;
+        files.push(archive::ExportedFile {
+            data: rendered(ctx, &db, &config, &config.data, Some(version.id))?,
+            source: match config.template {
+                true => Some(decrypted(ctx, &config, &config.data)?),
+                false => None,
+            },
+        });
+        entries.push(archive::ManifestEntry {
+            source: Some(archive::template_path(&file)).filter(|_| config.template),
+            file,
+            path: config.path,
+            kind: config.kind,
+            mode: config.mode,
+            mtime: config.mtime,
+            template: config.template,
+            encrypted: config.encrypted,
+        });
+    }
+    // templates are imported with placeholders, values travel with them
+    let variables = match entries.iter().any(|entry| entry.template) {
+        true => version_variables(&db, version.id)?.into_iter().collect(),
+        false => BTreeMap::new(),
+    };
+    let manifest = archive::Manifest {
+        version: version.name.clone(),
+        configs: entries,
+        variables,
+    };
+    if ctx.dry_run {
+        for entry in manifest.configs.iter() {
+            println!("dry run: export {} to {}", entry.path, entry.file);
+        }
+        println!("dry run: create {}", target);
+        return Ok(());
+    }
+    archive::export(target, &manifest, &files, secrets)?;
+    println!(
+        "{} configs of version {} exported to {}",
+        manifest.configs.len(),
+        version.name,
+        target
+    );
+    Ok(())
+}
+
+/// imports configs from directory tree or tarball, paths are taken from manifest
+/// of export or files are placed relative to target (first directory being package
+/// in stow layout), version is created when missing
+pub fn import(
+    ctx: &Context,
+    version: Option<&str>,
+    source: &str,
+    target: Option<&str>,
+    stow: bool,
+    exclude: &[&str],
+) -> Result<()> {
+    in_transaction(ctx, |db| {
+        let mut files: Vec<archive::TreeFile> = archive::read_tree(source)?;
+        let manifest: Option<archive::Manifest> = archive::take_manifest(&mut files)?;
+        let excluded = glob_set(&exclude.join("\n"))?;
+        let mut imported: Vec<(String, archive::TreeFile)> = vec![];
+        match &manifest {
+            Some(_) if target.is_some() || stow => {
+                return Err(Error::Usage(format!(
+                    "{} has manifest with paths of configs, --target and --stow do not apply",
+                    source
+                )))
+            }
+            Some(manifest) => {
+                for entry in manifest.configs.iter() {
+                    if excluded.is_match(&entry.file) {
+                        // excluded file (and its template) is not reported as missing in manifest
+                        files.retain(|tree_file| {
+                            tree_file.file != entry.file
+                                && Some(&tree_file.file) != entry.source.as_ref()
+                        });
+                        continue;
+                    }
+                    let index = files
+                        .iter()
+                        .position(|tree_file| tree_file.file == entry.file)
+                        .ok_or_else(|| {
+                            Error::NotFound(format!(
+                                "{} listed in manifest of {}",
+                                entry.file, source
+                            ))
+                        })?;
+                    let mut tree_file = files.remove(index);
+                    tree_file.kind = entry.kind;
+                    tree_file.mode = entry.mode;
+                    tree_file.mtime = entry.mtime;
+                    tree_file.template = entry.template;
+                    tree_file.encrypted = entry.encrypted;
+                    if let Some(template_file) = &entry.source {
+                        // template is stored with placeholders, not rendered
+                        let index = files
+                            .iter()
+                            .position(|tree_file| &tree_file.file == template_file)
+                            .ok_or_else(|| {
+                                Error::NotFound(format!(
+                                    "{} listed in manifest of {}",
+                                    template_file, source
+                                ))
+                            })?;
+                        tree_file.data = files.remove(index).data;
+                    }
+                    imported.push((paths::portable(&entry.path), tree_file));
+                }
+                for tree_file in files {
+                    println!("{} is not in manifest, skipped", tree_file.file);
+                }
+            }
+            None => {
+                let target = target.unwrap_or("~").trim_end_matches('/');
+                for tree_file in files {
+                    if excluded.is_match(&tree_file.file) {
+                        continue;
+                    }
+                    let relative = match stow {
+                        true => match archive::stow_path(&tree_file.file) {
+                            Some(relative) => relative,
+                            None => continue,
+                        },
+                        false => tree_file.file.clone(),
+                    };
+                    imported.push((
+                        paths::portable(&format!("{}/{}", target, relative)),
+                        tree_file,
+                    ));
+                }
+            }
+        }
+        let version: &str = match (version, &manifest) {
+            (Some(version), _) => version,
+            (None, Some(manifest)) => &manifest.version,
+            (None, None) => {
+                return Err(Error::Usage(
+                    "version has to be given (-v) when importing tree without manifest".to_string(),
+                ))
+            }
+        };
+        let configs = import_configs(ctx, db, version, imported)?;
+        done(
+            ctx,
+            format_args!(
+                "{} config record(s) of version {} in database created",
+                configs.len(),
+                version
+            ),
+        );
+        if let Some(manifest) = &manifest {
+            import_variables(ctx, db, &find_version(db, version)?, &manifest.variables)?;
+        }
+        Ok(())
+    })
+}
+
+/// prints line of tree list
+fn tree_item(index: usize, total_len: usize, shift_len: usize, item: &str) -> String {
+    let mut tree_branch = "├──";
+    if (index == 0 && total_len == 1) || index == total_len - 1 {
+        tree_branch = "└──";
+    }
+    format!("{}{} {}", " ".repeat(shift_len), tree_branch, item)
+}
+
+/// lists line separated list of configs stored in db
+pub fn list_configs(ctx: &Context, format: Format) -> Result<()> {
+    let db = get_db(ctx)?;
+    let configs: Vec<Config> = fetch_configs(&db)?;
+    if format != Format::Tree {
+        return print_configs(&db, &configs, format);
+    }
+    if configs.is_empty() {
+        println!("No configs in db");
+    } else {
+        for (version_name, config_vec) in group_by_version(&db, configs)? {
+            println!("================");
+            println!("{}", version_name);
+            for (i, config) in config_vec.iter().enumerate() {
+                println!(
+                    "{}",
+                    tree_item(
+                        i,
+                        config_vec.len(),
+                        version_name.len() + 1,
+                        &config_label(config)
+                    )
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// prints configs in machine readable format
+fn print_configs(db: &Connection, configs: &[Config], format: Format) -> Result<()> {
+    let mut records: Vec<output::ConfigRecord> = vec![];
+    for config in configs {
+        let latest: Vec<Revision> = Revision::select_where(
+            db,
+            "config_id=?1 ORDER BY id DESC LIMIT 1",
+            params![config.id],
+        )?;
+        records.push(output::ConfigRecord {
+            id: config.id,
+            version: Version::find(db, config.version_id)?.name,
+            path: config.path.clone(),
+            kind: config.kind.as_str(),
+            size: config.data.len(),
+            hash: content_hash(&config.data),
+            mode: config.mode,
+            mtime: config.mtime,
+            updated_at: latest.first().map(|revision| revision.created_at),
+            template: config.template,
+            encrypted: config.encrypted,
+        });
+    }
+    output::print_records(format, &records, |record| record.path.clone())
+}
+
+/// path of config as listed, symlinks show their target,
+/// templates and secrets are marked (contents are never shown)
+fn config_label(config: &Config) -> String {
+    match config.kind {
+        ConfigKind::File => {
+            let marks: Vec<&str> = [
+                (config.template, " (template)"),
+                (config.encrypted, " (encrypted)"),
+            ]
+            .iter()
+            .filter(|(marked, _)| *marked)
+            .map(|(_, mark)| *mark)
+            .collect();
+            format!("{}{}", config.path, marks.concat())
+        }
+        ConfigKind::Symlink => format!(
+            "{} -> {}",
+            config.path,
+            String::from_utf8_lossy(&config.data)
+        ),
+    }
+}
+
+/// groups configs by name of their version (sorted by version name)
+fn group_by_version(
+    db: &Connection,
+    configs: Vec<Config>,
+) -> Result<BTreeMap<String, Vec<Config>>> {
+    let mut map: BTreeMap<String, Vec<Config>> = BTreeMap::new();
+    for config in configs {
+        let version_name = Version::find(db, config.version_id)?.name;
+        map.entry(version_name).or_default().push(config);
+    }
+    Ok(map)
+}
+
+/// gets all the configs as a Vec<Config>
+fn fetch_configs(db: &Connection) -> Result<Vec<Config>> {
+    let configs: Vec<Config> = Config::all(db)?;
+    Ok(configs)
+}
+
+/// stores new revision of config data unless it is the same as the latest one
+fn snapshot(db: &Transaction, config_id: i32, data: &[u8]) -> Result<()> {
+    let hash = content_hash(data);
+    let latest: Vec<Revision> = Revision::select_where(
+        db,
+        "config_id=?1 ORDER BY id DESC LIMIT 1",
+        params![config_id],
+    )?;
+    if latest.iter().any(|revision| revision.hash == hash) {
+        return Ok(());
+    }
+    Revision {
+        id: Revision::next_id(db)?,
+        config_id,
+        data: data.to_vec(),
+        hash,
+        hostname: current_hostname(),
+        created_at: Local::now().timestamp(),
+    }
+    .create(db)?;
+    Ok(())
+}
+
+/// encrypts (or decrypts) data of config together with its revisions and backups,
+/// so no plain copy of secret is left in db
+fn set_encrypted(ctx: &Context, db: &Transaction, config: &Config, encrypted: bool) -> Result<()> {
+    let convert = |data: &[u8]| -> Result<Vec<u8>> {
+        match encrypted {
+            true => ctx.secrets.encrypt(data),
+            false => decrypted(ctx, config, data),
+        }
+    };
+    let data = convert(&config.data)?;
+    Config::update(db, config.id, "data", &data)?;
+    Config::update(db, config.id, "encrypted", &encrypted)?;
+    // bases of other machines point to revisions by hash
+    let mut hashes: HashMap<String, String> = HashMap::new();
+    for revision in Revision::select_where(db, "config_id=?1", params![config.id])? {
+        let revision_data = convert(&revision.data)?;
+        let hash = content_hash(&revision_data);
+        Revision::update(db, revision.id, "data", &revision_data)?;
+        Revision::update(db, revision.id, "hash", &hash)?;
+        hashes.insert(revision.hash, hash);
+    }
+    for state in SyncState::select_where(db, "config_id=?1", params![config.id])? {
+        if let Some(hash) = hashes.get(&state.hash) {
+            SyncState::update(db, state.id, "hash", hash)?;
+        }
+    }
+    for backup in Backup::select_where(
+        db,
+        "path=?1 AND encrypted=?2",
+        params![config.path, !encrypted],
+    )? {
+        if let Some(backup_data) = &backup.data {
+            Backup::update(db, backup.id, "data", &convert(backup_data)?)?;
+        }
+        Backup::update(db, backup.id, "encrypted", &encrypted)?;
+    }
+    snapshot(db, config.id, &data)?;
+    set_base(db, config.id, &data)
+}
+
+/// remembers data as last synced state of config on this machine
+fn set_base(db: &Transaction, config_id: i32, data: &[u8]) -> Result<()> {
+    set_base_hash(db, config_id, Some(content_hash(data)))
+}
+
+/// sets hash of sync base of config on this machine, None removes the base
+fn set_base_hash(db: &Transaction, config_id: i32, hash: Option<String>) -> Result<()> {
+    let hostname = current_hostname();
+    SyncState::delete(
+        db,
+        "config_id=?1 AND hostname=?2",
+        params![config_id, hostname],
+    )?;
+    if let Some(hash) = hash {
+        SyncState {
+            id: SyncState::next_id(db)?,
+            config_id,
+            hostname,
+            hash,
+        }
+        .create(db)?;
+    }
+    Ok(())
+}
+
+/// content hash of config data at last sync on this machine
+fn base_hash(db: &Connection, config_id: i32) -> Result<Option<String>> {
+    let states: Vec<SyncState> = SyncState::select_where(
+        db,
+        "config_id=?1 AND hostname=?2",
+        params![config_id, current_hostname()],
+    )?;
+    Ok(states.into_iter().next().map(|state| state.hash))
+}
+
+/// deletes configs matching condition (revisions and sync states by cascade)
+fn delete_configs(db: &Transaction, condition: &str, params: &[&dyn ToSql]) -> Result<()> {
+    Config::delete(db, condition, params)?;
+    Ok(())
+}
+
+/// reads file and stores it as new config of version
+/// symlinks are stored as links unless follow is set
+fn insert_config(
+    ctx: &Context,
+    db: &Transaction,
+    path: &str,
+    version_id: i32,
+    follow: bool,
+    encrypt: bool,
+) -> Result<Config> {
+    let mut config = Config {
+        id: Config::next_id(db)?,
+        version_id,
+        path: path.to_string(),
+        kind: if !follow && is_symlink(&paths::expand(path)?) {
+            ConfigKind::Symlink
+        } else {
+            ConfigKind::File
+        },
+        ..Config::default()
+    };
+    config.data = read_config(&config)?;
+    if encrypt {
+        config.encrypted = true;
+        config.data = ctx.secrets.encrypt(&config.data)?;
+    }
+    record_metadata(&mut config)?;
+    config.create(db)?;
+    snapshot(db, config.id, &config.data)?;
+    set_base(db, config.id, &config.data)?;
+    Ok(config)
+}
+
+/// adds files of tracked directory that are not configs of its version yet
+/// returns paths of added files
+fn add_untracked_files(
+    ctx: &Context,
+    db: &Transaction,
+    tracked_dir: &TrackedDir,
+) -> Result<Vec<String>> {
+    let include = glob_set(&tracked_dir.include)?;
+    let exclude = glob_set(&tracked_dir.exclude)?;
+    let root = paths::expand(&tracked_dir.path)?;
+    let mut added: Vec<String> = vec![];
+    for entry in WalkDir::new(&root).sort_by_file_name() {
+        let entry = entry.map_err(|e| {
+            let path = e.path().map_or(root.clone(), |p| p.display().to_string());
+            Error::io(&path, e.into())
+        })?;
+        if !entry.file_type().is_file() && !entry.file_type().is_symlink() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(&root)
+            .unwrap_or_else(|_| entry.path());
+        if (!include.is_empty() && !include.is_match(relative)) || exclude.is_match(relative) {
+            continue;
+        }
+        // stored under path of tracked dir as written there (e.g. ~/.config/nvim/init.lua)
+        let path = format!("{}/{}", tracked_dir.path, relative.to_string_lossy());
+        let existing: Vec<Config> = Config::select_where(
+            db,
+            "path=?1 AND version_id=?2",
+            params![path, tracked_dir.version_id],
+        )?;
+        if existing.is_empty() {
+            insert_config(ctx, db, &path, tracked_dir.version_id, false, false)?;
+            added.push(path);
+        }
+    }
+    Ok(added)
+}
+
+/// builds glob matcher out of newline separated globs
+fn glob_set(globs: &str) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs.lines().filter(|glob| !glob.is_empty()) {
+        builder.add(
+            Glob::new(glob).map_err(|e| Error::Usage(format!("invalid glob {}: {}", glob, e)))?,
+        );
+    }
+    builder
+        .build()
+        .map_err(|e| Error::Usage(format!("invalid globs: {}", e)))
+}
+
+/// version with given name followed by its parents (closest first)
+fn version_chain(db: &Connection, name: &str) -> Result<Vec<Version>> {
+    let mut chain: Vec<Version> = vec![find_version(db, name)?];
+    while let Some(parent_id) = chain.last().and_then(|version| version.parent_id) {
+        if chain.iter().any(|version| version.id == parent_id) {
+            return Err(Error::Conflict(format!(
+                "parents of version {} make a cycle",
+                name
+            )));
+        }
+        chain.push(Version::find(db, parent_id)?);
+    }
+    Ok(chain)
+}
+
+/// comma separated ids of versions for IN clause
+fn chain_ids(chain: &[Version]) -> String {
+    chain
+        .iter()
+        .map(|version| version.id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// finds version by its name
+fn find_version(db: &Connection, name: &str) -> Result<Version> {
+    Version::select_where(db, "name=?1", params![name])?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::NotFound(format!("version {}", name)))
+}
+
+/// reads what config of given kind stores from (expanded) location,
+/// file contents or symlink target
+fn read_disk(location: &str, kind: ConfigKind) -> io::Result<Vec<u8>> {
+    match kind {
+        ConfigKind::File => fs::read(location),
+        ConfigKind::Symlink => Ok(fs::read_link(location)?.into_os_string().into_vec()),
+    }
+}
+
+/// reads what config stores from disk, errors carry its location
+fn read_config(config: &Config) -> Result<Vec<u8>> {
+    let location = paths::expand(&config.path)?;
+    read_disk(&location, config.kind).map_err(|e| Error::io(&location, e))
+}
+
+/// writes data to config location, as file contents or (re)created symlink
+fn write_config(ctx: &Context, config: &Config, data: &[u8]) -> Result<()> {
+    let location = paths::expand(&config.path)?;
+    if ctx.dry_run {
+        let change = match fs::symlink_metadata(&location) {
+            Ok(_) => "overwrite",
+            Err(_) => "create",
+        };
+        println!("dry run: {} {} ({} bytes)", change, location, data.len());
+        return Ok(());
+    }
+    match config.kind {
+        ConfigKind::File => write_file(&location, data),
+        ConfigKind::Symlink => {
+            let temp = temp_path(&location);
+            create_parent(&location)
+                .and_then(|_| std::os::unix::fs::symlink(OsString::from_vec(data.to_vec()), &temp))
+                .and_then(|_| fs::rename(&temp, &location))
+                .map_err(|e| {
+                    let _ = fs::remove_file(&temp);
+                    Error::io(&location, e)
+                })
+        }
+    }
+}
+
+/// backs up current contents of config location into write batch, then writes data
+fn write_backed_up(
+    ctx: &Context,
+    db: &Transaction,
+    batch: i32,
+    config: &Config,
+    data: &[u8],
+) -> Result<()> {
+    let location = paths::expand(&config.path)?;
+    let previous: Option<Vec<u8>> = match read_disk(&location, config.kind) {
+        Ok(previous) if config.encrypted => Some(ctx.secrets.encrypt(&previous)?),
+        Ok(previous) => Some(previous),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(Error::io(&location, e)),
+    };
+    // backup is recorded only for file actually written
+    write_config(ctx, config, data)?;
+    Backup {
+        id: Backup::next_id(db)?,
+        batch,
+        path: config.path.clone(),
+        kind: config.kind,
+        data: previous,
+        hostname: current_hostname(),
+        created_at: Local::now().timestamp(),
+        encrypted: config.encrypted,
+        config_id: Some(config.id),
+        base_hash: base_hash(db, config.id)?,
+        written_hash: Some(content_hash(data)),
+    }
+    .create(db)?;
+    Ok(())
+}
+
+/// number of new write batch
+fn next_batch(db: &Connection) -> Result<i32> {
+    let last: Vec<Backup> = Backup::select_where(db, "1 ORDER BY batch DESC LIMIT 1", &[])?;
+    Ok(last.first().map_or(1, |backup| backup.batch + 1))
+}
+
+/// drops backups of this machine older than last KEPT_BACKUP_BATCHES batches
+fn prune_backups(db: &Transaction) -> Result<()> {
+    Backup::delete(
+        db,
+        "hostname=?1 AND batch NOT IN (SELECT DISTINCT batch FROM backups
+        WHERE hostname=?1 ORDER BY batch DESC LIMIT ?2)",
+        params![current_hostname(), KEPT_BACKUP_BATCHES],
+    )?;
+    Ok(())
+}
+
+/// writes whole file atomically (temp file in the same directory, fsync, rename),
+/// symlink at path is followed and its target written, missing directories are created,
+/// errors carry its path
+fn write_file(path: &str, data: &[u8]) -> Result<()> {
+    let target: String = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::canonicalize(path)
+            .map_or(path.to_string(), |target| {
+                target.to_string_lossy().into_owned()
+            }),
+        _ => path.to_string(),
+    };
+    let temp = temp_path(&target);
+    let write = || -> io::Result<()> {
+        create_parent(&target)?;
+        let mut file = fs::File::create(&temp)?;
+        io::Write::write_all(&mut file, data)?;
+        if let Ok(previous) = fs::metadata(&target) {
+            file.set_permissions(previous.permissions())?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp, &target)?;
+        if let Some(directory) = Path::new(&target)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            fs::File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        Error::io(path, e)
+    })
+}
+
+/// creates missing directories leading to path
+fn create_parent(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => fs::create_dir_all(directory),
+        _ => Ok(()),
+    }
+}
+
+/// temporary sibling of path, renamed over it once fully written
+fn temp_path(path: &str) -> String {
+    let path = Path::new(path);
+    let name = path
+        .file_name()
+        .map_or("config".into(), |name| name.to_string_lossy());
+    path.with_file_name(format!(
+        ".{}.rusty-configs-{}.tmp",
+        name,
+        std::process::id()
+    ))
+    .to_string_lossy()
+    .into_owned()
+}
+
+/// fills mode, owner, group and mtime of config from its file
+/// (symlinks only have owner and group, their mode and mtime are not restorable)
+fn record_metadata(config: &mut Config) -> Result<()> {
+    let location = paths::expand(&config.path)?;
+    let metadata = match config.kind {
+        ConfigKind::File => fs::metadata(&location),
+        ConfigKind::Symlink => fs::symlink_metadata(&location),
+    }
+    .map_err(|e| Error::io(&location, e))?;
+    let file = config.kind == ConfigKind::File;
+    config.mode = Some(metadata.mode() & 0o7777).filter(|_| file);
+    config.uid = Some(metadata.uid());
+    config.gid = Some(metadata.gid());
+    config.mtime = Some(metadata.mtime()).filter(|_| file);
+    Ok(())
+}
+
+/// saves file metadata fields of config into db
+fn store_metadata(db: &Transaction, config: &Config) -> Result<()> {
+    Config::update(db, config.id, "mode", &config.mode)?;
+    Config::update(db, config.id, "uid", &config.uid)?;
+    Config::update(db, config.id, "gid", &config.gid)?;
+    Config::update(db, config.id, "mtime", &config.mtime)?;
+    Ok(())
+}
+
+/// applies recorded mode and (if owner is set) ownership of config to its file
+fn restore_metadata(ctx: &Context, config: &Config, owner: bool) -> Result<()> {
+    if ctx.dry_run {
+        return Ok(());
+    }
+    let location = paths::expand(&config.path)?;
+    if let Some(mode) = config.mode {
+        fs::set_permissions(&location, fs::Permissions::from_mode(mode))
+            .map_err(|e| Error::io(&location, e))?;
+    }
+    if owner && (config.uid.is_some() || config.gid.is_some()) {
+        // only owner and group differing from the file are changed
+        let current = fs::symlink_metadata(&location).map_err(|e| Error::io(&location, e))?;
+        let uid = config.uid.filter(|uid| *uid != current.uid());
+        let gid = config.gid.filter(|gid| *gid != current.gid());
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+        // only root can give files away, others keep owning what they wrote
+        if unsafe { libc::geteuid() } != 0 {
+            println!(
+                "{} owner and group not restored (not running as root)",
+                config.path
+            );
+            return Ok(());
+        }
+        let chown = match config.kind {
+            ConfigKind::File => std::os::unix::fs::chown,
+            ConfigKind::Symlink => std::os::unix::fs::lchown,
+        };
+        chown(&location, uid, gid).map_err(|e| Error::io(&location, e))?;
+    }
+    Ok(())
+}
+
+/// applies recorded mtime of config to its file
+/// (opened read-only, setting explicit times needs only ownership, so read-only modes are fine)
+fn restore_mtime(ctx: &Context, config: &Config) -> Result<()> {
+    if let Some(mtime) = config.mtime.filter(|mtime| *mtime >= 0 && !ctx.dry_run) {
+        let location = paths::expand(&config.path)?;
+        fs::File::open(&location)
+            .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime as u64)))
+            .map_err(|e| Error::io(&location, e))?;
+    }
+    Ok(())
+}
+
+/// true if path itself is a symlink (not following it)
+fn is_symlink(path: &str) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+/// sha256 of data as hex string
+fn content_hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// name of machine we are running on
+fn current_hostname() -> String {
+    hostname::get()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// formats unix timestamp as local date and time
+fn format_timestamp(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(datetime) => datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// parses local date (and optional time) into unix timestamp
+/// accepts "YYYY-MM-DD", "YYYY-MM-DD HH:MM" and "YYYY-MM-DD HH:MM:SS"
+fn parse_date(date: &str) -> Result<i64> {
+    let datetime: NaiveDateTime = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|day| day.and_hms_opt(23, 59, 59))
+        })
+        .ok_or_else(|| {
+            Error::Usage(format!(
+                "could not parse date {}, use YYYY-MM-DD [HH:MM[:SS]]",
+                date
+            ))
+        })?;
+    Local
+        .from_local_datetime(&datetime)
+        .earliest()
+        .map(|datetime| datetime.timestamp())
+        .ok_or_else(|| Error::Usage(format!("date {} does not exist in local timezone", date)))
+}
+
+/// gets configs matching all criteria of selector
+/// selected version is resolved with its parents, config of child overrides
+/// config of parent with the same path
+fn select_configs(db: &Connection, selector: &Selector) -> Result<Vec<Config>> {
+    let mut conditions: Vec<String> = vec!["1".to_string()];
+    let mut values: Vec<String> = vec![];
+    if let Some(path) = selector.path {
+        conditions.push("path=?".to_string());
+        values.push(paths::portable(path));
+    }
+    if let Some(name) = selector.name {
+        conditions.push("path LIKE ? ESCAPE '\\'".to_string());
+        values.push(like_suffix(name));
+    }
+    let chain: Option<Vec<Version>> = match selected_version(db, selector)? {
+        Some(version) => Some(version_chain(db, &version)?),
+        None => None,
+    };
+    if let Some(chain) = &chain {
+        conditions.push(format!("version_id IN ({})", chain_ids(chain)));
+    }
+    let params: Vec<&dyn ToSql> = values.iter().map(|value| value as &dyn ToSql).collect();
+    let mut configs: Vec<Config> = Config::select_where(db, &conditions.join(" AND "), &params)?;
+    if let Some(chain) = &chain {
+        let layer = |config: &Config| chain.iter().position(|layer| layer.id == config.version_id);
+        configs.sort_by_key(layer);
+        let mut seen: HashSet<String> = HashSet::new();
+        configs.retain(|config| seen.insert(config.path.clone()));
+        configs.sort_by_key(|config| config.id);
+    }
+    if let Some(glob) = selector.glob {
+        let matcher = glob_set(glob)?;
+        configs.retain(|config| {
+            matcher.is_match(&config.path)
+                || paths::expand(&config.path).is_ok_and(|location| matcher.is_match(location))
+        });
+    }
+    Ok(configs)
+}
+
+/// effective configs of version together with chain of versions they come from
+fn version_configs(db: &Connection, name: &str) -> Result<(Vec<Version>, Vec<Config>)> {
+    let chain: Vec<Version> = version_chain(db, name)?;
+    let selector = Selector {
+        version: Some(name),
+        ..Selector::default()
+    };
+    Ok((chain, select_configs(db, &selector)?))
+}
+
+/// version selector narrows configs to, given one or default version of machine
+fn selected_version(db: &Connection, selector: &Selector) -> Result<Option<String>> {
+    match selector.version {
+        Some(version) => Ok(Some(version.to_string())),
+        None if selector.all_versions => Ok(None),
+        None => Ok(default_version(db)?.map(|(version, _)| version)),
+    }
+}
+
+/// default version of this machine together with where it comes from,
+/// RUSTY_CONFIGS_VERSION overrides version bound to hostname by bind
+fn default_version(db: &Connection) -> Result<Option<(String, &'static str)>> {
+    preferred_version(db, std::env::var(VERSION_VARIABLE).ok())
+}
+
+/// default version of this machine for given value of RUSTY_CONFIGS_VERSION
+/// (unset or empty variable leaves it to binding)
+fn preferred_version(
+    db: &Connection,
+    variable: Option<String>,
+) -> Result<Option<(String, &'static str)>> {
+    if let Some(version) = variable.filter(|version| !version.is_empty()) {
+        return Ok(Some((version, "environment variable")));
+    }
+    let machines: Vec<Machine> =
+        Machine::select_where(db, "hostname=?1", params![current_hostname()])?;
+    match machines.first() {
+        Some(machine) => Ok(Some((
+            Version::find(db, machine.version_id)?.name,
+            "binding",
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// id of version whose variables render templates of selected configs,
+/// None when configs are not narrowed to one version (each uses its own)
+fn template_scope(db: &Connection, selector: &Selector) -> Result<Option<i32>> {
+    match selected_version(db, selector)? {
+        Some(version) => Ok(Some(find_version(db, &version)?.id)),
+        None => Ok(None),
+    }
+}
+
+/// variables of version including the ones of its parents (closer layer wins)
+fn version_variables(db: &Connection, version_id: i32) -> Result<HashMap<String, String>> {
+    let chain = version_chain(db, &Version::find(db, version_id)?.name)?;
+    let mut variables: Vec<Variable> =
+        Variable::select_where(db, &format!("version_id IN ({})", chain_ids(&chain)), &[])?;
+    variables.sort_by_key(|variable| {
+        std::cmp::Reverse(
+            chain
+                .iter()
+                .position(|layer| layer.id == variable.version_id),
+        )
+    });
+    Ok(variables
+        .into_iter()
+        .map(|variable| (variable.name, variable.value))
+        .collect())
+}
+
+/// contents of config as written to disk, secrets are decrypted
+/// and templates rendered with variables of scope (or of their own version)
+fn rendered(
+    ctx: &Context,
+    db: &Connection,
+    config: &Config,
+    data: &[u8],
+    scope: Option<i32>,
+) -> Result<Vec<u8>> {
+    let data = decrypted(ctx, config, data)?;
+    if !config.template {
+        return Ok(data);
+    }
+    let text = std::str::from_utf8(&data)
+        .map_err(|_| Error::Decode(format!("template {} is not valid UTF-8", config.path)))?;
+    let variables = version_variables(db, scope.unwrap_or(config.version_id))?;
+    match template::render(text, &variables) {
+        Ok(rendered) => Ok(rendered.into_bytes()),
+        Err(Error::NotFound(variable)) => {
+            Err(Error::NotFound(format!("{} of {}", variable, config.path)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// contents of config as stored in db for file read from disk,
+/// secrets are encrypted and hand edits of rendered templates are mapped back
+/// to the template (None when edited lines contained placeholders)
+fn unrendered(
+    ctx: &Context,
+    db: &Connection,
+    config: &Config,
+    disk: &[u8],
+    scope: Option<i32>,
+) -> Result<Option<Vec<u8>>> {
+    if !config.encrypted && (!config.template || disk == config.data) {
+        return Ok(Some(disk.to_vec()));
+    }
+    if disk == rendered(ctx, db, config, &config.data, scope)?.as_slice() {
+        // unchanged secrets keep their ciphertext
+        return Ok(Some(config.data.clone()));
+    }
+    let data: Vec<u8> = match config.template {
+        false => disk.to_vec(),
+        true => {
+            let edited = match std::str::from_utf8(disk) {
+                Ok(edited) => edited,
+                Err(_) => return Ok(None),
+            };
+            let template = decrypted(ctx, config, &config.data)?;
+            let text = std::str::from_utf8(&template).map_err(|_| {
+                Error::Decode(format!("template {} is not valid UTF-8", config.path))
+            })?;
+            let variables = version_variables(db, scope.unwrap_or(config.version_id))?;
+            match template::reverse(text, &variables, edited)? {
+                Some(reversed) => reversed.into_bytes(),
+                None => return Ok(None),
+            }
+        }
+    };
+    match config.encrypted {
+        true => Ok(Some(ctx.secrets.encrypt(&data)?)),
+        false => Ok(Some(data)),
+    }
+}
+
+/// data of config as it is, encrypted one decrypted
+fn decrypted(ctx: &Context, config: &Config, data: &[u8]) -> Result<Vec<u8>> {
+    if !config.encrypted {
+        return Ok(data.to_vec());
+    }
+    ctx.secrets.decrypt(data).map_err(|e| match e {
+        Error::Decode(message) => Error::Decode(format!("{}: {}", config.path, message)),
+        e => e,
+    })
+}
+
+/// stores imported files as configs of version (created when missing),
+/// fails when any path clashes with existing config of version
+/// (files marked encrypted by manifest are encrypted again)
+fn import_configs(
+    ctx: &Context,
+    db: &Transaction,
+    version: &str,
+    imported: Vec<(String, archive::TreeFile)>,
+) -> Result<Vec<Config>> {
+    let version: Version = match Version::select_where(db, "name=?1", params![version])?
+        .into_iter()
+        .next()
+    {
+        Some(version) => version,
+        None => {
+            let new_version = Version {
+                id: Version::next_id(db)?,
+                name: version.to_string(),
+                parent_id: None,
+            };
+            new_version.create(db)?;
+            done(ctx, format_args!("Version {} created", new_version.name));
+            new_version
+        }
+    };
+    let mut paths: HashSet<String> =
+        Config::select_where(db, "version_id=?1", params![version.id])?
+            .into_iter()
+            .map(|config| config.path)
+            .collect();
+    let clashing: Vec<&str> = imported
+        .iter()
+        .filter(|(path, _)| !paths.insert(path.clone()))
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if !clashing.is_empty() {
+        return Err(Error::Conflict(format!(
+            "configs already in version {} (or imported twice): {}",
+            version.name,
+            clashing.join(", ")
+        )));
+    }
+    let mut configs: Vec<Config> = vec![];
+    for (path, tree_file) in imported {
+        let config = Config {
+            id: Config::next_id(db)?,
+            version_id: version.id,
+            path,
+            data: match tree_file.encrypted {
+                true => ctx.secrets.encrypt(&tree_file.data)?,
+                false => tree_file.data,
+            },
+            mode: tree_file.mode,
+            mtime: tree_file.mtime,
+            kind: tree_file.kind,
+            template: tree_file.template,
+            encrypted: tree_file.encrypted,
+            ..Config::default()
+        };
+        config.create(db)?;
+        snapshot(db, config.id, &config.data)?;
+        configs.push(config);
+    }
+    Ok(configs)
+}
+
+/// creates variables of imported templates missing in version,
+/// existing ones keep their values
+fn import_variables(
+    ctx: &Context,
+    db: &Transaction,
+    version: &Version,
+    variables: &BTreeMap<String, String>,
+) -> Result<()> {
+    let existing = version_variables(db, version.id)?;
+    for (name, value) in variables {
+        match existing.get(name) {
+            Some(current) if current != value => println!(
+                "Variable {} of version {} kept as {} (imported {})",
+                name, version.name, current, value
+            ),
+            Some(_) => {}
+            None => {
+                Variable {
+                    id: Variable::next_id(db)?,
+                    version_id: version.id,
+                    name: name.to_string(),
+                    value: value.to_string(),
+                }
+                .create(db)?;
+                done(
+                    ctx,
+                    format_args!("Variable {} of version {} created", name, version.name),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// builds LIKE pattern matching given suffix literally (wildcards in it are escaped)
+fn like_suffix(suffix: &str) -> String {
+    let escaped = suffix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}", escaped)
+}
+
+/// testing version entity, create and fetch
+#[test]
+fn version_entity() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    let test_version = Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    };
+    test_version.create(&db)?;
+
+    // all
+    let versions: Vec<Version> = Version::all(&db)?;
+    assert_eq!(1, versions[0].id);
+    assert_eq!("home", versions[0].name);
+
+    // find
+    let version: Version = Version::find(&db, 1)?;
+    assert_eq!(1, version.id);
+    assert_eq!("home".to_string(), version.name);
+
+    // update
+    Version::update(&db, version.id, "name", &"work")?;
+    let updated_version: Version = Version::find(&db, 1)?;
+    assert_eq!(1, updated_version.id);
+    assert_eq!("work".to_string(), updated_version.name);
+
+    Ok(())
+}
+
+/// testing config entity, create and fetch
+#[test]
 fn config_entity() -> Result<()> {
-    let db = get_db(DEFAULT_DB_LOCATION);
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    }
+    .create(&db)?;
+    let test_config = Config {
+        id: 1,
+        version_id: 1,
+        path: "/tmp/test".to_string(),
+        data: b"first line\nsecond line".to_vec(),
+        ..Config::default()
+    };
+    test_config.create(&db)?;
+
+    // all
+    let configs: Vec<Config> = Config::all(&db)?;
+    assert_eq!(1, configs[0].id);
+    assert_eq!("/tmp/test", configs[0].path);
+    assert_eq!(b"first line\nsecond line".to_vec(), configs[0].data);
+
+    // find
+    let config: Config = Config::find(&db, 1)?;
+    assert_eq!(1, config.id);
+    assert_eq!(1, config.version_id);
+    assert_eq!("/tmp/test".to_string(), config.path);
+
+    // update
+    Config::update(&db, config.id, "path", &"/tmp/test2")?;
+    let updated_config: Config = Config::find(&db, 1)?;
+    assert_eq!(1, updated_config.id);
+    assert_eq!("/tmp/test2".to_string(), updated_config.path);
+
+    Ok(())
+}
+
+/// testing that quotes and sql fragments in values round-trip as plain data
+#[test]
+fn quoted_values() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    let test_version = Version {
+        id: 1,
+        name: "it's'; DROP TABLE versions; --".to_string(),
+        ..Version::default()
+    };
+    test_version.create(&db)?;
+    let test_config = Config {
+        id: 1,
+        version_id: 1,
+        path: "/home/test/.config/it's/app.conf".to_string(),
+        data: b"first line".to_vec(),
+        ..Config::default()
+    };
+    test_config.create(&db)?;
+
+    // select_where
+    let versions: Vec<Version> = Version::select_where(&db, "name=?1", params![test_version.name])?;
+    assert_eq!(1, versions.len());
+    assert_eq!(test_version.name, versions[0].name);
+
+    // update
+    Config::update(&db, 1, "path", &"/tmp/o'neil")?;
+    let configs: Vec<Config> = Config::select_where(&db, "path=?1", params!["/tmp/o'neil"])?;
+    assert_eq!(1, configs.len());
+
+    // delete
+    Config::delete(
+        &db,
+        "path LIKE ?1 ESCAPE '\\'",
+        params![like_suffix("o'neil")],
+    )?;
+    assert_eq!(0, Config::all(&db)?.len());
+    assert_eq!(1, Version::all(&db)?.len());
+
+    Ok(())
+}
+
+/// testing that every distinct snapshot of config data is kept
+#[test]
+fn config_revisions() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    }
+    .create(&db)?;
+    Config {
+        id: 1,
+        version_id: 1,
+        path: "/tmp/test".to_string(),
+        data: b"first".to_vec(),
+        ..Config::default()
+    }
+    .create(&db)?;
+
+    // snapshot
+    snapshot(&db, 1, b"first")?;
+    snapshot(&db, 1, b"first")?;
+    snapshot(&db, 1, b"second")?;
+    let revisions: Vec<Revision> = Revision::select_where(&db, "config_id=?1", params![1])?;
+    assert_eq!(2, revisions.len());
+    assert_eq!(b"first".to_vec(), revisions[0].data);
+    assert_eq!(b"second".to_vec(), revisions[1].data);
+    assert_eq!(content_hash(b"second"), revisions[1].hash);
+
+    Ok(())
+}
+
+/// testing selection of configs by path, name and version
+#[test]
+fn config_selector() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    for (id, name) in [(1, "home"), (2, "work")].iter() {
+        Version {
+            id: *id,
+            name: name.to_string(),
+            ..Version::default()
+        }
+        .create(&db)?;
+    }
+    for (id, version_id, path) in
+        [(1, 1, "/tmp/a_b"), (2, 2, "/tmp/a_b"), (3, 1, "/tmp/axb")].iter()
+    {
+        Config {
+            id: *id,
+            version_id: *version_id,
+            path: path.to_string(),
+            data: vec![],
+            ..Config::default()
+        }
+        .create(&db)?;
+    }
+
+    // selection
+    let ids = |selector: Selector| -> Result<Vec<i32>> {
+        Ok(select_configs(&db, &selector)?
+            .iter()
+            .map(|config| config.id)
+            .collect())
+    };
+    assert_eq!(vec![1, 2, 3], ids(Selector::default())?);
+    assert_eq!(
+        vec![1, 2],
+        ids(Selector {
+            name: Some("a_b"),
+            ..Selector::default()
+        })?
+    );
+    assert_eq!(
+        vec![1],
+        ids(Selector {
+            path: Some("/tmp/a_b"),
+            version: Some("home"),
+            ..Selector::default()
+        })?
+    );
+    assert_eq!(
+        vec![1, 3],
+        ids(Selector {
+            glob: Some("/tmp/a?b"),
+            version: Some("home"),
+            ..Selector::default()
+        })?
+    );
+
+    Ok(())
+}
+
+/// testing diff headers and whether differences are reported
+#[test]
+fn config_differences() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("rusty-configs-diff-{}", std::process::id()));
+    fs::create_dir_all(&root).expect("create temp dir");
+    let ctx = &Context {
+        db: root.join("db.sqlite").to_string_lossy().into_owned(),
+        ..Context::default()
+    };
+    let path = root.join("hosts").to_string_lossy().into_owned();
+    let config = Config {
+        id: 1,
+        version_id: 1,
+        path: path.clone(),
+        data: b"127.0.0.1 db\n".to_vec(),
+        ..Config::default()
+    };
+
+    // headers
+    let lines = config_diff(&config, Some(b"127.0.0.1 disk\n"), &config.data);
+    assert_eq!(format!("--- a{}", path), lines[0]);
+    assert_eq!(format!("+++ b{}", path), lines[1]);
+    assert_eq!("-127.0.0.1 disk", lines[3]);
+    assert_eq!("+127.0.0.1 db", lines[4]);
+    let home = Config {
+        path: "~/.bashrc".to_string(),
+        ..Config::default()
+    };
+    let lines = config_diff(&home, None, b"ls\n");
+    assert_eq!(
+        vec!["--- /dev/null", "+++ b/~/.bashrc"],
+        lines[..2].to_vec()
+    );
+    let secret = Config {
+        encrypted: true,
+        ..home
+    };
+    assert_eq!(
+        vec!["Encrypted files a/~/.bashrc and b/~/.bashrc differ"],
+        config_diff(&secret, Some(b"a"), b"b")
+    );
+
+    // differences decide exit code
+    let mut db = open_db(&ctx.db)?;
+    migrations::migrate(&db)?;
+    all_or_nothing(&mut db, |db| {
+        Version {
+            id: 1,
+            name: "home".to_string(),
+            ..Version::default()
+        }
+        .create(db)?;
+        config.create(db)?;
+        Ok(())
+    })?;
+    let selector = Selector {
+        all_versions: true,
+        ..Selector::default()
+    };
+    assert!(diff(ctx, &selector)?);
+    write_file(&path, &config.data)?;
+    assert!(!diff(ctx, &selector)?);
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
+
+/// testing classification of configs against last synced base
+#[test]
+fn config_status_classification() -> Result<()> {
+    let ctx = &Context::default();
+    let mut db = Connection::open_in_memory()?;
+    let path = std::env::temp_dir().join(format!("rusty-configs-status-{}", std::process::id()));
+    let path = path.to_str().expect("utf8 temp path").to_string();
 
     // setup
-    Config::table(&db)?;
-    let test_config = Config {
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    }
+    .create(&db)?;
+    let mut config = Config {
         id: 1,
         version_id: 1,
-        path: "/tmp/test".to_string(),
-        data: vec!["first line".to_string(), "second line".to_string()],
+        path: path.clone(),
+        data: b"base".to_vec(),
+        ..Config::default()
     };
-    test_config.create(&db)?;
+    config.create(&db)?;
 
-    // all
+    // classification
+    assert_eq!(
+        Status::MissingOnDisk,
+        config_status(ctx, &db, &config, None)?
+    );
+    fs::write(&path, b"base").expect("write temp file");
+    assert_eq!(Status::Unchanged, config_status(ctx, &db, &config, None)?);
+    fs::write(&path, b"disk").expect("write temp file");
+    assert_eq!(Status::Diverged, config_status(ctx, &db, &config, None)?);
+    set_base(&db, 1, b"base")?;
+    assert_eq!(
+        Status::ModifiedOnDisk,
+        config_status(ctx, &db, &config, None)?
+    );
+    fs::write(&path, b"base").expect("write temp file");
+    config.data = b"db".to_vec();
+    assert_eq!(
+        Status::ModifiedInDb,
+        config_status(ctx, &db, &config, None)?
+    );
+
+    fs::remove_file(&path).expect("remove temp file");
+    Ok(())
+}
+
+/// testing that files of tracked directory are added according to globs
+#[test]
+fn tracked_directory() -> Result<()> {
+    let ctx = &Context::default();
+    let mut db = Connection::open_in_memory()?;
+    let root = std::env::temp_dir().join(format!("rusty-configs-dir-{}", std::process::id()));
+    fs::create_dir_all(root.join("lua/plugins")).expect("create temp dir");
+    fs::write(root.join("init.lua"), b"init").expect("write temp file");
+    fs::write(root.join("lua/plugins/a.lua"), b"a").expect("write temp file");
+    fs::write(root.join("lua/plugins/a.lua.bak"), b"bak").expect("write temp file");
+    fs::write(root.join("lua/skip.lua"), b"skip").expect("write temp file");
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    }
+    .create(&db)?;
+    let tracked_dir = TrackedDir {
+        id: 1,
+        version_id: 1,
+        path: root.to_string_lossy().into_owned(),
+        include: "*.lua".to_string(),
+        exclude: "lua/skip.lua".to_string(),
+    };
+    tracked_dir.create(&db)?;
+
+    // initial walk
+    let added = add_untracked_files(ctx, &db, &tracked_dir)?;
+    let relative: Vec<String> = added
+        .iter()
+        .map(|path| path[tracked_dir.path.len()..].to_string())
+        .collect();
+    assert_eq!(vec!["/init.lua", "/lua/plugins/a.lua"], relative);
+
+    // only new files are picked up later
+    fs::write(root.join("lua/new.lua"), b"new").expect("write temp file");
+    let added = add_untracked_files(ctx, &db, &tracked_dir)?;
+    assert_eq!(1, added.len());
+    assert!(added[0].ends_with("/lua/new.lua"));
+    assert_eq!(3, Config::all(&db)?.len());
+
+    // file vanished from directory is skipped by read
+    fs::remove_file(root.join("lua/new.lua")).expect("remove temp file");
+    fs::write(root.join("init.lua"), b"changed").expect("write temp file");
+    let selector = Selector {
+        all_versions: true,
+        ..Selector::default()
+    };
+    read_configs(ctx, &db, &selector)?;
     let configs: Vec<Config> = Config::all(&db)?;
-    assert_eq!(1, configs[0].id);
-    assert_eq!("/tmp/test", configs[0].path);
+    assert_eq!(b"changed".to_vec(), configs[0].data);
+    assert_eq!(b"new".to_vec(), configs[2].data);
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
+
+/// testing that recorded mode and mtime are reapplied to rewritten file
+#[test]
+fn file_metadata() -> Result<()> {
+    let ctx = &Context::default();
+    let path = std::env::temp_dir()
+        .join(format!("rusty-configs-meta-{}", std::process::id()))
+        .to_string_lossy()
+        .into_owned();
+    write_file(&path, b"#!/bin/sh")?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).expect("chmod temp file");
+
+    // record
+    let mut config = Config {
+        path: path.clone(),
+        ..Config::default()
+    };
+    record_metadata(&mut config)?;
+    assert_eq!(Some(0o750), config.mode);
+    config.mtime = Some(1_000_000_000);
+
+    // restore after plain rewrite
+    fs::remove_file(&path).expect("remove temp file");
+    write_file(&path, b"#!/bin/sh")?;
+    restore_metadata(ctx, &config, false)?;
+    restore_mtime(ctx, &config)?;
+    let metadata = fs::metadata(&path).expect("stat temp file");
+    assert_eq!(0o750, metadata.mode() & 0o7777);
+    assert_eq!(1_000_000_000, metadata.mtime());
+
+    // matching owner and group need no chown (works without root)
+    assert!(config.uid.is_some() && config.gid.is_some());
+    restore_metadata(ctx, &config, true)?;
+
+    // restore of read-only mode (ssh keys) before mtime
+    config.mode = Some(0o400);
+    config.mtime = Some(1_100_000_000);
+    restore_metadata(ctx, &config, false)?;
+    restore_mtime(ctx, &config)?;
+    let metadata = fs::metadata(&path).expect("stat temp file");
+    assert_eq!(0o400, metadata.mode() & 0o7777);
+    assert_eq!(1_100_000_000, metadata.mtime());
+
+    fs::remove_file(&path).expect("remove temp file");
+    Ok(())
+}
+
+/// testing that symlink configs store and recreate link target
+#[test]
+fn symlink_config() -> Result<()> {
+    let ctx = &Context::default();
+    let root = std::env::temp_dir().join(format!("rusty-configs-link-{}", std::process::id()));
+    fs::create_dir_all(&root).expect("create temp dir");
+    let target = root.join("target").to_string_lossy().into_owned();
+    let link = root.join("link").to_string_lossy().into_owned();
+    write_file(&target, b"contents")?;
+    std::os::unix::fs::symlink(&target, &link).expect("create temp symlink");
+
+    // preserved link stores its target
+    assert!(is_symlink(&link));
+    let mut config = Config {
+        path: link.clone(),
+        kind: ConfigKind::Symlink,
+        ..Config::default()
+    };
+    config.data = read_config(&config)?;
+    assert_eq!(target.as_bytes(), config.data.as_slice());
+
+    // link replaced by regular file is recreated
+    fs::remove_file(&link).expect("remove temp symlink");
+    write_file(&link, b"copy")?;
+    write_config(ctx, &config, &config.data)?;
+    assert!(is_symlink(&link));
     assert_eq!(
-        vec!["first line".to_string(), "second line".to_string()],
-        configs[0].data
+        b"contents".to_vec(),
+        fs::read(&link).expect("read temp symlink")
     );
 
-    // find
-    let config: Config = Config::find(&db, 1)?;
-    assert_eq!(1, config.id);
-    assert_eq!(1, config.version_id);
-    assert_eq!("/tmp/test".to_string(), config.path);
+    // link is created in missing directories
+    let nested = Config {
+        path: root.join("missing/link").to_string_lossy().into_owned(),
+        kind: ConfigKind::Symlink,
+        data: config.data.clone(),
+        ..Config::default()
+    };
+    write_config(ctx, &nested, &nested.data)?;
+    assert!(is_symlink(&nested.path));
 
-    // update
-    Config::update(&db, config.id, "path", "/tmp/test2")?;
-    let updated_config: Config = Config::find(&db, 1)?;
-    assert_eq!(1, updated_config.id);
-    assert_eq!("/tmp/test2".to_string(), updated_config.path);
+    // followed link stores contents of target
+    config.kind = ConfigKind::File;
+    assert_eq!(b"contents".to_vec(), read_config(&config)?);
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
+
+/// testing that writes keep previous contents in batches and follow symlinks
+#[test]
+fn backed_up_writes() -> Result<()> {
+    let ctx = &Context::default();
+    let mut db = Connection::open_in_memory()?;
+    let root = std::env::temp_dir().join(format!("rusty-configs-backup-{}", std::process::id()));
+    fs::create_dir_all(&root).expect("create temp dir");
+    let existing = root.join("existing").to_string_lossy().into_owned();
+    let link = root.join("link").to_string_lossy().into_owned();
+    write_file(&existing, b"old")?;
+    fs::set_permissions(&existing, fs::Permissions::from_mode(0o600)).expect("chmod temp file");
+    std::os::unix::fs::symlink(&existing, &link).expect("create temp symlink");
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    assert_eq!(1, next_batch(&db)?);
+
+    // batch
+    let config = |path: &str| Config {
+        path: path.to_string(),
+        ..Config::default()
+    };
+    let created = root.join("created").to_string_lossy().into_owned();
+    write_backed_up(ctx, &db, 1, &config(&existing), b"new")?;
+    write_backed_up(ctx, &db, 1, &config(&created), b"created")?;
+    let backups: Vec<Backup> = Backup::all(&db)?;
+    assert_eq!(Some(b"old".to_vec()), backups[0].data);
+    assert_eq!(None, backups[1].data);
+    assert_eq!(2, next_batch(&db)?);
+    assert_eq!(
+        b"new".to_vec(),
+        fs::read(&existing).expect("read temp file")
+    );
+    assert_eq!(
+        0o600,
+        fs::metadata(&existing).expect("stat").mode() & 0o7777
+    );
+
+    // missing directories are created
+    let nested = root
+        .join("missing/dir/nested")
+        .to_string_lossy()
+        .into_owned();
+    write_backed_up(ctx, &db, 1, &config(&nested), b"nested")?;
+    assert_eq!(
+        b"nested".to_vec(),
+        fs::read(&nested).expect("read temp file")
+    );
+
+    // writing through symlink keeps the link
+    write_file(&link, b"linked")?;
+    assert!(is_symlink(&link));
+    assert_eq!(
+        b"linked".to_vec(),
+        fs::read(&existing).expect("read temp file")
+    );
+
+    // only last batches are kept
+    for batch in 2..=KEPT_BACKUP_BATCHES + 1 {
+        write_backed_up(ctx, &db, batch, &config(&existing), b"again")?;
+    }
+    prune_backups(&db)?;
+    let backups: Vec<Backup> = Backup::all(&db)?;
+    assert_eq!(KEPT_BACKUP_BATCHES as usize, backups.len());
+    assert!(backups.iter().all(|backup| backup.batch > 1));
+
+    // undo restores file and sync base changed by write
+    Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    }
+    .create(&db)?;
+    let tracked = Config {
+        id: 1,
+        version_id: 1,
+        ..config(&existing)
+    };
+    tracked.create(&db)?;
+    set_base(&db, 1, b"base")?;
+    let batch = next_batch(&db)?;
+    write_backed_up(ctx, &db, batch, &tracked, b"stored")?;
+    set_base(&db, 1, b"stored")?;
+    undo_last_batch(ctx, &db)?;
+    assert_eq!(
+        b"again".to_vec(),
+        fs::read(&existing).expect("read temp file")
+    );
+    assert_eq!(Some(content_hash(b"base")), base_hash(&db, 1)?);
+    undo_last_batch(ctx, &db)?;
+    assert_eq!(Some(content_hash(b"base")), base_hash(&db, 1)?);
+
+    // undo refuses to overwrite file changed after the write
+    let batch = next_batch(&db)?;
+    write_backed_up(ctx, &db, batch, &tracked, b"written")?;
+    write_file(&existing, b"edited")?;
+    assert!(matches!(undo_last_batch(ctx, &db), Err(Error::Conflict(_))));
+    assert_eq!(
+        b"edited".to_vec(),
+        fs::read(&existing).expect("read temp file")
+    );
+    fs::remove_file(&existing).expect("remove temp file");
+    undo_last_batch(ctx, &db)?;
+    assert_eq!(
+        b"again".to_vec(),
+        fs::read(&existing).expect("read temp file")
+    );
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
+
+/// testing precedence of version selecting configs: -v, environment variable,
+/// machine binding, then all versions
+#[test]
+fn machine_binding() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    for (id, name) in [(1, "home"), (2, "work")].iter() {
+        Version {
+            id: *id,
+            name: name.to_string(),
+            ..Version::default()
+        }
+        .create(&db)?;
+        Config {
+            id: *id,
+            version_id: *id,
+            path: format!("/tmp/{}", name),
+            ..Config::default()
+        }
+        .create(&db)?;
+    }
+    let ids = |selector: Selector| -> Result<Vec<i32>> {
+        Ok(select_configs(&db, &selector)?
+            .iter()
+            .map(|config| config.id)
+            .collect())
+    };
+    assert_eq!(vec![1, 2], ids(Selector::default())?);
+
+    // binding
+    Machine {
+        id: 1,
+        hostname: current_hostname(),
+        version_id: 2,
+    }
+    .create(&db)?;
+    assert_eq!(Some(("work".to_string(), "binding")), default_version(&db)?);
+    assert_eq!(vec![2], ids(Selector::default())?);
+    assert_eq!(
+        vec![1],
+        ids(Selector {
+            version: Some("home"),
+            ..Selector::default()
+        })?
+    );
+    assert_eq!(
+        vec![1, 2],
+        ids(Selector {
+            all_versions: true,
+            ..Selector::default()
+        })?
+    );
+
+    // environment variable overrides binding unless empty
+    assert_eq!(
+        Some(("home".to_string(), "environment variable")),
+        preferred_version(&db, Some("home".to_string()))?
+    );
+    assert_eq!(
+        Some(("work".to_string(), "binding")),
+        preferred_version(&db, Some(String::new()))?
+    );
+    assert_eq!(
+        Some(("work".to_string(), "binding")),
+        preferred_version(&db, None)?
+    );
+
+    // without binding nothing is preferred and all versions are selected
+    Machine::delete(&db, "1", &[])?;
+    assert_eq!(None, preferred_version(&db, None)?);
+    assert_eq!(vec![1, 2], ids(Selector::default())?);
+
+    Ok(())
+}
+
+/// testing that configs of child version override parent ones by path
+#[test]
+fn layered_versions() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    for (id, name, parent_id) in [(1, "base", None), (2, "home", Some(1))].iter() {
+        Version {
+            id: *id,
+            name: name.to_string(),
+            parent_id: *parent_id,
+        }
+        .create(&db)?;
+    }
+    for (id, version_id, path) in [
+        (1, 1, "/tmp/.bashrc"),
+        (2, 1, "/tmp/.vimrc"),
+        (3, 2, "/tmp/.bashrc"),
+    ]
+    .iter()
+    {
+        Config {
+            id: *id,
+            version_id: *version_id,
+            path: path.to_string(),
+            ..Config::default()
+        }
+        .create(&db)?;
+    }
+
+    // resolution
+    let ids = |version: &str| -> Result<Vec<i32>> {
+        let selector = Selector {
+            version: Some(version),
+            ..Selector::default()
+        };
+        Ok(select_configs(&db, &selector)?
+            .iter()
+            .map(|config| config.id)
+            .collect())
+    };
+    assert_eq!(vec![1, 2], ids("base")?);
+    assert_eq!(vec![2, 3], ids("home")?);
+
+    // cycle
+    Version::update(&db, 1, "parent_id", &2)?;
+    assert!(matches!(
+        version_chain(&db, "home"),
+        Err(Error::Conflict(_))
+    ));
+
+    Ok(())
+}
+
+/// testing that templates render with variables of version and map edits back
+#[test]
+fn template_configs() -> Result<()> {
+    let ctx = &Context::default();
+    let mut db = Connection::open_in_memory()?;
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    for (id, name, parent_id) in [(1, "base", None), (2, "work", Some(1))].iter() {
+        Version {
+            id: *id,
+            name: name.to_string(),
+            parent_id: *parent_id,
+        }
+        .create(&db)?;
+    }
+    for (id, version_id, name, value) in [
+        (1, 1, "email", "me@home"),
+        (2, 1, "editor", "vim"),
+        (3, 2, "email", "me@work"),
+    ]
+    .iter()
+    {
+        Variable {
+            id: *id,
+            version_id: *version_id,
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+        .create(&db)?;
+    }
+    let config = Config {
+        id: 1,
+        version_id: 1,
+        path: "/tmp/.gitconfig".to_string(),
+        data: b"email={{ email }}\neditor={{ editor }}\n".to_vec(),
+        template: true,
+        ..Config::default()
+    };
+    config.create(&db)?;
+    assert!(Config::find(&db, 1)?.template);
+
+    // rendering (variables of selected version override its parents)
+    assert_eq!(
+        b"email=me@home\neditor=vim\n".to_vec(),
+        rendered(ctx, &db, &config, &config.data, None)?
+    );
+    assert_eq!(
+        b"email=me@work\neditor=vim\n".to_vec(),
+        rendered(ctx, &db, &config, &config.data, Some(2))?
+    );
+    Variable::delete(&db, "id=?1", params![2])?;
+    assert!(matches!(
+        rendered(ctx, &db, &config, &config.data, None),
+        Err(Error::NotFound(_))
+    ));
+
+    // mapping edits back
+    Variable {
+        id: 2,
+        version_id: 2,
+        name: "editor".to_string(),
+        value: "nano".to_string(),
+    }
+    .create(&db)?;
+    assert_eq!(
+        Some(config.data.clone()),
+        unrendered(ctx, &db, &config, b"email=me@work\neditor=nano\n", Some(2))?
+    );
+    assert_eq!(
+        Some(b"email={{ email }}\neditor={{ editor }}\nname=me\n".to_vec()),
+        unrendered(
+            ctx,
+            &db,
+            &config,
+            b"email=me@work\neditor=nano\nname=me\n",
+            Some(2)
+        )?
+    );
+    assert_eq!(
+        None,
+        unrendered(ctx, &db, &config, b"email=other\neditor=nano\n", Some(2))?
+    );
+
+    Ok(())
+}
+
+/// testing that secret configs never reach db in plain form
+#[test]
+fn encrypted_configs() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+    let root = std::env::temp_dir().join(format!("rusty-configs-secret-{}", std::process::id()));
+    fs::create_dir_all(&root).expect("create temp dir");
+    let key_file = root.join("key").to_string_lossy().into_owned();
+    let netrc = root.join("netrc").to_string_lossy().into_owned();
+    write_file(&key_file, b"0123456789abcdef")?;
+    write_file(&netrc, b"password secret\n")?;
+    let ctx = &Context {
+        secrets: secret::Secrets::new(Some(&key_file)),
+        ..Context::default()
+    };
+
+    // setup
+    migrations::migrate(&db)?;
+    let db = db.transaction()?;
+    Version {
+        id: 1,
+        name: "home".to_string(),
+        ..Version::default()
+    }
+    .create(&db)?;
+    let config = insert_config(ctx, &db, &netrc, 1, false, true)?;
+    let stored = Config::find(&db, config.id)?;
+    let plain = |data: &[u8]| data.windows(6).any(|window| window == b"secret");
+    assert!(stored.encrypted);
+    assert!(!plain(&stored.data));
+    assert!(config_label(&stored).ends_with("(encrypted)"));
+
+    // decrypted on write, unchanged file keeps ciphertext, changed one is encrypted again
+    assert_eq!(
+        b"password secret\n".to_vec(),
+        rendered(ctx, &db, &stored, &stored.data, None)?
+    );
+    assert_eq!(
+        Some(stored.data.clone()),
+        unrendered(ctx, &db, &stored, b"password secret\n", None)?
+    );
+    let changed = unrendered(ctx, &db, &stored, b"password other\n", None)?.expect("changed data");
+    assert!(changed.starts_with(b"rcenc1"));
+    assert_eq!(Status::Unchanged, config_status(ctx, &db, &stored, None)?);
+
+    // decryption of config with its revisions
+    set_encrypted(ctx, &db, &stored, false)?;
+    assert_eq!(b"password secret\n".to_vec(), Config::find(&db, 1)?.data);
+    assert!(Revision::all(&db)?
+        .iter()
+        .all(|revision| revision.data == b"password secret\n"));
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
+
+/// testing that import creates version and stores nothing when a path clashes
+#[test]
+fn imported_configs() -> Result<()> {
+    let ctx = &Context::default();
+    let mut db = Connection::open_in_memory()?;
+    let tree_file = |file: &str| archive::TreeFile {
+        file: file.to_string(),
+        kind: ConfigKind::File,
+        data: file.as_bytes().to_vec(),
+        mode: Some(0o644),
+        mtime: None,
+        template: false,
+        encrypted: false,
+    };
+
+    // setup
+    migrations::migrate(&db)?;
+
+    // version is created
+    let configs = all_or_nothing(&mut db, |db| {
+        import_configs(
+            ctx,
+            db,
+            "home",
+            vec![
+                ("~/.bashrc".to_string(), tree_file("bash/dot-bashrc")),
+                ("~/.vimrc".to_string(), tree_file("vim/dot-vimrc")),
+            ],
+        )
+    })?;
+    assert_eq!(2, configs.len());
+    assert_eq!(1, find_version(&db, "home")?.id);
+    assert_eq!(b"vim/dot-vimrc".to_vec(), Config::find(&db, 2)?.data);
+    assert_eq!(2, Revision::all(&db)?.len());
+
+    // clash rolls back whole import
+    assert!(matches!(
+        all_or_nothing(&mut db, |db| import_configs(
+            ctx,
+            db,
+            "home",
+            vec![
+                ("~/.zshrc".to_string(), tree_file("zsh/dot-zshrc")),
+                ("~/.bashrc".to_string(), tree_file("bash/dot-bashrc")),
+            ],
+        )),
+        Err(Error::Conflict(_))
+    ));
+    assert!(matches!(
+        all_or_nothing(&mut db, |db| import_configs(
+            ctx,
+            db,
+            "work",
+            vec![
+                ("~/.zshrc".to_string(), tree_file("zsh/dot-zshrc")),
+                ("~/.zshrc".to_string(), tree_file("dot-zshrc")),
+            ],
+        )),
+        Err(Error::Conflict(_))
+    ));
+    assert_eq!(2, Config::all(&db)?.len());
+    assert_eq!(1, Version::all(&db)?.len());
+
+    // template flag of manifest is kept
+    let template = archive::TreeFile {
+        template: true,
+        ..tree_file("git/dot-gitconfig")
+    };
+    let configs = all_or_nothing(&mut db, |db| {
+        import_configs(
+            ctx,
+            db,
+            "home",
+            vec![("~/.gitconfig".to_string(), template)],
+        )
+    })?;
+    assert!(configs[0].template && Config::find(&db, configs[0].id)?.template);
+
+    Ok(())
+}
+
+/// testing that exported template comes back with its placeholders and variables
+#[test]
+fn exported_templates() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("rusty-configs-export-{}", std::process::id()));
+    fs::create_dir_all(&root).expect("create temp dir");
+    let context = |name: &str| Context {
+        db: root.join(name).to_string_lossy().into_owned(),
+        ..Context::default()
+    };
+    let (source, copy) = (context("source.sqlite"), context("copy.sqlite"));
+    let tree = root.join("tree").to_string_lossy().into_owned();
+    let path = root.join("gitconfig").to_string_lossy().into_owned();
+
+    // setup
+    for ctx in [&source, &copy] {
+        migrations::migrate(&open_db(&ctx.db)?)?;
+    }
+    all_or_nothing(&mut open_db(&source.db)?, |db| {
+        Version {
+            id: 1,
+            name: "home".to_string(),
+            ..Version::default()
+        }
+        .create(db)?;
+        Variable {
+            id: 1,
+            version_id: 1,
+            name: "email".to_string(),
+            value: "me@home".to_string(),
+        }
+        .create(db)?;
+        Config {
+            id: 1,
+            version_id: 1,
+            path: path.clone(),
+            data: b"email={{ email }}".to_vec(),
+            template: true,
+            ..Config::default()
+        }
+        .create(db)?;
+        Ok(())
+    })?;
+
+    // round trip
+    export(&source, "home", &tree, false)?;
+    import(&copy, None, &tree, None, false, &[])?;
+    let db = open_db(&copy.db)?;
+    let config = Config::find(&db, 1)?;
+    assert!(config.template);
+    assert_eq!(b"email={{ email }}".to_vec(), config.data);
+    assert_eq!(
+        Some(&"me@home".to_string()),
+        version_variables(&db, config.version_id)?.get("email")
+    );
+    assert_eq!(
+        b"email=me@home".to_vec(),
+        rendered(&copy, &db, &config, &config.data, None)?
+    );
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
+
+/// testing that dry run leaves database and files untouched
+#[test]
+fn dry_run_commands() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("rusty-configs-dry-{}", std::process::id()));
+    fs::create_dir_all(&root).expect("create temp dir");
+    let ctx = &Context {
+        db: root.join("db.sqlite").to_string_lossy().into_owned(),
+        dry_run: true,
+        ..Context::default()
+    };
+    let path = root.join("bashrc").to_string_lossy().into_owned();
+
+    // setup
+    let mut db = open_db(&ctx.db)?;
+    migrations::migrate(&db)?;
+    all_or_nothing(&mut db, |db| {
+        Version {
+            id: 1,
+            name: "home".to_string(),
+            ..Version::default()
+        }
+        .create(db)?;
+        Config {
+            id: 1,
+            version_id: 1,
+            path: path.clone(),
+            data: b"alias ll='ls -l'".to_vec(),
+            ..Config::default()
+        }
+        .create(db)?;
+        Ok(())
+    })?;
+    drop(db);
+    let stored = fs::read(&ctx.db).expect("read temp db");
+
+    // nothing is written or deleted
+    let selector = Selector {
+        all_versions: true,
+        ..Selector::default()
+    };
+    write_all(ctx, &selector, false)?;
+    delete_version(ctx, "home")?;
+    assert!(!Path::new(&path).exists());
+    assert_eq!(stored, fs::read(&ctx.db).expect("read temp db"));
+    let db = open_db(&ctx.db)?;
+    assert_eq!(1, Config::all(&db)?.len());
+    assert!(Backup::all(&db)?.is_empty());
+
+    fs::remove_dir_all(&root).expect("remove temp dir");
+    Ok(())
+}
+
+/// testing that failing write command keeps only backups and sync bases of written files
+#[test]
+fn failed_writes() -> Result<()> {
+    let ctx = &Context::default();
+    let mut db = Connection::open_in_memory()?;
+    let path = std::env::temp_dir()
+        .join(format!("rusty-configs-failed-{}", std::process::id()))
+        .to_string_lossy()
+        .into_owned();
+    write_file(&path, b"old")?;
+
+    // setup
+    migrations::migrate(&db)?;
+    all_or_nothing(&mut db, |db| {
+        Version {
+            id: 1,
+            name: "home".to_string(),
+            ..Version::default()
+        }
+        .create(db)?;
+        for (id, config_path) in [(1, path.as_str()), (2, "~/.read")] {
+            Config {
+                id,
+                version_id: 1,
+                path: config_path.to_string(),
+                data: b"old".to_vec(),
+                ..Config::default()
+            }
+            .create(db)?;
+            set_base(db, id, b"old")?;
+        }
+        Ok(())
+    })?;
+
+    // first config written, second one read from disk, then failure
+    let result: Result<()> = keeping_writes(&mut db, |db| {
+        let written = Config::find(db, 1)?;
+        write_backed_up(ctx, db, 1, &written, b"new")?;
+        set_base(db, 1, b"new")?;
+        Config::update(db, 2, "data", &b"read".to_vec())?;
+        snapshot(db, 2, b"read")?;
+        set_base(db, 2, b"read")?;
+        Err(Error::Conflict("failure".to_string()))
+    });
+    assert!(matches!(result, Err(Error::Conflict(_))));
+    assert_eq!(b"new".to_vec(), fs::read(&path).expect("read temp file"));
+    let backups: Vec<Backup> = Backup::all(&db)?;
+    assert_eq!(1, backups.len());
+    assert_eq!(Some(content_hash(b"old")), backups[0].base_hash);
+    assert_eq!(Some(content_hash(b"new")), base_hash(&db, 1)?);
+    assert_eq!(b"old".to_vec(), Config::find(&db, 2)?.data);
+    assert_eq!(Some(content_hash(b"old")), base_hash(&db, 2)?);
+    assert!(Revision::all(&db)?.is_empty());
+
+    // failing undo keeps restored file undone
+    let result: Result<()> = keeping_writes(&mut db, |db| {
+        undo_last_batch(ctx, db)?;
+        Err(Error::Conflict("failure".to_string()))
+    });
+    assert!(result.is_err());
+    assert_eq!(b"old".to_vec(), fs::read(&path).expect("read temp file"));
+    assert!(Backup::all(&db)?.is_empty());
+    assert_eq!(Some(content_hash(b"old")), base_hash(&db, 1)?);
+
+    // nothing is written when last config fails to render
+    let ctx = &Context {
+        db: format!("{}.sqlite", path),
+        ..Context::default()
+    };
+    let mut db = open_db(&ctx.db)?;
+    migrations::migrate(&db)?;
+    all_or_nothing(&mut db, |db| {
+        Version {
+            id: 1,
+            name: "home".to_string(),
+            ..Version::default()
+        }
+        .create(db)?;
+        Config {
+            id: 1,
+            version_id: 1,
+            path: path.clone(),
+            data: b"new".to_vec(),
+            ..Config::default()
+        }
+        .create(db)?;
+        Config {
+            id: 2,
+            version_id: 1,
+            path: format!("{}.template", path),
+            data: b"name={{ missing }}".to_vec(),
+            template: true,
+            ..Config::default()
+        }
+        .create(db)?;
+        Ok(())
+    })?;
+    let selector = Selector {
+        all_versions: true,
+        ..Selector::default()
+    };
+    assert!(matches!(
+        write_all(ctx, &selector, false),
+        Err(Error::NotFound(_))
+    ));
+    assert_eq!(b"old".to_vec(), fs::read(&path).expect("read temp file"));
+    assert!(Backup::all(&db)?.is_empty());
 
+    fs::remove_file(&path).expect("remove temp file");
+    fs::remove_file(&ctx.db).expect("remove temp db");
     Ok(())
 }
@@ -0,0 +1,162 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+use super::{Error, Result};
+
+/// output of listing and status commands
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// human readable tree (when --format is not given)
+    #[default]
+    Tree,
+    /// one record per line, main fields separated by space
+    Plain,
+    /// array of objects
+    Json,
+    /// tab separated values with header line of field names
+    Tsv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<Self, Self::Err> {
+        match format {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "tsv" => Ok(Format::Tsv),
+            _ => Err(format!(
+                "unknown format {}, options: json, tsv, plain",
+                format
+            )),
+        }
+    }
+}
+
+/// version as listed
+#[derive(Debug, Default, Serialize)]
+pub struct VersionRecord {
+    pub id: i32,
+    pub name: String,
+    /// name of parent version
+    pub parent: Option<String>,
+    /// number of configs stored in version itself
+    pub configs: usize,
+}
+
+/// config as listed (version is the one config is stored in)
+#[derive(Debug, Default, Serialize)]
+pub struct ConfigRecord {
+    pub id: i32,
+    pub version: String,
+    pub path: String,
+    pub kind: &'static str,
+    /// bytes of data as stored
+    pub size: usize,
+    /// sha256 of data as stored
+    pub hash: String,
+    pub mode: Option<u32>,
+    /// modification time of file (unix timestamp)
+    pub mtime: Option<i64>,
+    /// time of latest revision (unix timestamp)
+    pub updated_at: Option<i64>,
+    pub template: bool,
+    pub encrypted: bool,
+}
+
+/// variable as listed (version is the one variable is set in)
+#[derive(Debug, Default, Serialize)]
+pub struct VariableRecord {
+    pub id: i32,
+    pub version: String,
+    pub name: String,
+    pub value: String,
+}
+
+/// status of config
+#[derive(Debug, Default, Serialize)]
+pub struct StatusRecord {
+    pub id: i32,
+    pub version: String,
+    pub path: String,
+    /// unchanged, modified-on-disk, modified-in-db, diverged, missing-on-disk or unreadable
+    pub status: &'static str,
+    /// two letter code of --porcelain
+    pub code: &'static str,
+}
+
+/// revision of config
+#[derive(Debug, Default, Serialize)]
+pub struct RevisionRecord {
+    pub id: i32,
+    pub config_id: i32,
+    pub version: String,
+    pub path: String,
+    pub size: usize,
+    pub hash: String,
+    pub hostname: String,
+    /// unix timestamp
+    pub created_at: i64,
+}
+
+/// prints records in machine readable format, plain line of record is built by closure
+/// (tree format is printed by commands themselves)
+pub fn print_records<T: Serialize + Default>(
+    format: Format,
+    records: &[T],
+    plain: impl Fn(&T) -> String,
+) -> Result<()> {
+    match format {
+        Format::Tree | Format::Plain => {
+            for record in records {
+                println!("{}", plain(record));
+            }
+        }
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(records).map_err(encoding)?
+        ),
+        Format::Tsv => {
+            // header comes from default record, so it is printed even for empty list
+            if let Value::Object(fields) = serde_json::to_value(T::default()).map_err(encoding)? {
+                println!("{}", fields.keys().cloned().collect::<Vec<_>>().join("\t"));
+            }
+            for record in records {
+                if let Value::Object(fields) = serde_json::to_value(record).map_err(encoding)? {
+                    let values: Vec<String> = fields.values().map(tsv_value).collect();
+                    println!("{}", values.join("\t"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// field of tsv line, null is empty and tabs or newlines are escaped
+fn tsv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n"),
+        value => value.to_string(),
+    }
+}
+
+/// error of serialization
+fn encoding(e: serde_json::Error) -> Error {
+    Error::Decode(format!("output: {}", e))
+}
+
+/// testing escaping of tsv fields
+#[test]
+fn tsv_values() {
+    assert_eq!("", tsv_value(&Value::Null));
+    assert_eq!("a\\tb\\nc", tsv_value(&Value::from("a\tb\nc")));
+    assert_eq!("420", tsv_value(&Value::from(420)));
+    assert_eq!("true", tsv_value(&Value::from(true)));
+    assert_eq!(Ok(Format::Tsv), "tsv".parse::<Format>().map_err(|_| ()));
+    assert!("tree".parse::<Format>().is_err());
+}
@@ -0,0 +1,138 @@
+use std::env;
+
+use super::{Error, Result};
+
+/// variable home directory is read from
+static HOME: &str = "HOME";
+/// variable of user config directory (defaults to ~/.config when unset)
+static XDG_CONFIG_HOME: &str = "XDG_CONFIG_HOME";
+
+/// expands stored path for this machine
+/// leading ~ is replaced by home directory and $NAME / ${NAME} by environment variables
+pub fn expand(path: &str) -> Result<String> {
+    expand_with(path, &|name| env::var(name).ok())
+}
+
+/// rewrites absolute path under home directory (or non-default $XDG_CONFIG_HOME)
+/// into portable form, other paths are returned unchanged
+pub fn portable(path: &str) -> String {
+    portable_with(path, &|name| env::var(name).ok())
+}
+
+/// expand with variables looked up by given closure
+fn expand_with(path: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let variable = |name: &str| -> Result<String> {
+        lookup(name)
+            .or_else(|| match name {
+                "XDG_CONFIG_HOME" => lookup(HOME).map(|home| format!("{}/.config", home)),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                Error::Usage(format!(
+                    "variable {} used in path {} is not set",
+                    name, path
+                ))
+            })
+    };
+
+    let mut expanded = String::new();
+    let mut rest: &str = path;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&variable(HOME)?);
+        rest = &rest[1..];
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let (name, skip) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => return Err(Error::Usage(format!("unclosed ${{ in path {}", path))),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        if name.is_empty() {
+            // lone $ is kept as part of the path
+            expanded.push('$');
+            continue;
+        }
+        expanded.push_str(&variable(name)?);
+        rest = &rest[skip..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// portable with variables looked up by given closure
+fn portable_with(path: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+    let home = match lookup(HOME) {
+        Some(home) if !home.is_empty() && home != "/" => home.trim_end_matches('/').to_string(),
+        _ => return path.to_string(),
+    };
+    if let Some(config_home) = lookup(XDG_CONFIG_HOME) {
+        let config_home = config_home.trim_end_matches('/');
+        if !config_home.is_empty() && config_home != format!("{}/.config", home) {
+            if let Some(rest) = under(path, config_home) {
+                return format!("${}{}", XDG_CONFIG_HOME, rest);
+            }
+        }
+    }
+    match under(path, &home) {
+        Some(rest) => format!("~{}", rest),
+        None => path.to_string(),
+    }
+}
+
+/// remainder of path (empty or starting with slash) if it lies under directory
+fn under<'p>(path: &'p str, directory: &str) -> Option<&'p str> {
+    path.strip_prefix(directory)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// testing expansion and portable form of paths
+#[test]
+fn path_expansion() -> Result<()> {
+    let lookup = |name: &str| match name {
+        "HOME" => Some("/home/viktor".to_string()),
+        "DOTFILES" => Some("/srv/dots".to_string()),
+        _ => None,
+    };
+
+    // expansion
+    assert_eq!("/home/viktor/.bashrc", expand_with("~/.bashrc", &lookup)?);
+    assert_eq!(
+        "/home/viktor/.bashrc",
+        expand_with("$HOME/.bashrc", &lookup)?
+    );
+    assert_eq!(
+        "/home/viktor/.config/nvim/init.lua",
+        expand_with("${XDG_CONFIG_HOME}/nvim/init.lua", &lookup)?
+    );
+    assert_eq!("/srv/dots/vimrc", expand_with("$DOTFILES/vimrc", &lookup)?);
+    assert_eq!("/tmp/~a/$/b", expand_with("/tmp/~a/$/b", &lookup)?);
+    assert!(expand_with("$MISSING/file", &lookup).is_err());
+    assert!(expand_with("${HOME/file", &lookup).is_err());
+
+    // portable form
+    assert_eq!("~/.bashrc", portable_with("/home/viktor/.bashrc", &lookup));
+    assert_eq!(
+        "/home/viktorka/.bashrc",
+        portable_with("/home/viktorka/.bashrc", &lookup)
+    );
+    assert_eq!("/etc/hosts", portable_with("/etc/hosts", &lookup));
+    let custom = |name: &str| match name {
+        "XDG_CONFIG_HOME" => Some("/data/config/".to_string()),
+        name => lookup(name),
+    };
+    assert_eq!(
+        "$XDG_CONFIG_HOME/nvim/init.lua",
+        portable_with("/data/config/nvim/init.lua", &custom)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::cell::RefCell;
+use std::io::IsTerminal;
+
+use super::{Error, Result};
+
+/// variable passphrase is read from (prompted for when unset and no key file is given)
+pub static PASSPHRASE_VARIABLE: &str = "RUSTY_CONFIGS_PASSPHRASE";
+
+/// start of encrypted data, tells format of the rest (salt, nonce, ciphertext)
+const MAGIC: &[u8] = b"rcenc1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// source of secret (passphrase or key file) and keys derived from it during one run
+#[derive(Debug, Default)]
+pub struct Secrets {
+    /// key file given by --key-file (used instead of passphrase)
+    key_file: Option<String>,
+    /// passphrase or key file contents, read once per run
+    secret: RefCell<Option<Vec<u8>>>,
+    /// keys derived from secret by their salt (derivation is slow on purpose)
+    keys: RefCell<Vec<([u8; SALT_LEN], Key)>>,
+}
+
+impl Secrets {
+    /// secrets read from key file, or from passphrase when there is none
+    pub fn new(key_file: Option<&str>) -> Secrets {
+        Secrets {
+            key_file: key_file.map(str::to_string),
+            ..Secrets::default()
+        }
+    }
+
+    /// encrypts data with key derived from passphrase or key file
+    /// (salt is shared by everything encrypted during one run, nonce is always fresh)
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let known: Option<([u8; SALT_LEN], Key)> = self.keys.borrow().first().cloned();
+        let (salt, key) = match known {
+            Some(known) => known,
+            None => {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                (salt, self.key(&salt, true)?)
+            }
+        };
+        seal(data, &key, &salt)
+    }
+
+    /// decrypts data encrypted by encrypt
+    /// (wrong passphrase or key file and damaged data are reported as Decode)
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        open(data, |salt| self.key(salt, false))
+    }
+
+    /// key for salt, derived from secret unless known from earlier
+    fn key(&self, salt: &[u8; SALT_LEN], confirm: bool) -> Result<Key> {
+        if let Some((_, key)) = self.keys.borrow().iter().find(|(known, _)| known == salt) {
+            return Ok(*key);
+        }
+        let key = derive_key(&self.secret(confirm)?, salt)?;
+        self.keys.borrow_mut().push((*salt, key));
+        Ok(key)
+    }
+
+    /// passphrase (from environment or prompt) or contents of key file,
+    /// new passphrase has to be typed twice
+    fn secret(&self, confirm: bool) -> Result<Vec<u8>> {
+        if let Some(secret) = self.secret.borrow().as_ref() {
+            return Ok(secret.clone());
+        }
+        let secret: Vec<u8> = match &self.key_file {
+            Some(path) => std::fs::read(path).map_err(|e| Error::io(path, e))?,
+            None => match std::env::var(PASSPHRASE_VARIABLE) {
+                Ok(passphrase) => passphrase.into_bytes(),
+                Err(_) => prompt(confirm)?.into_bytes(),
+            },
+        };
+        if secret.is_empty() {
+            return Err(Error::Usage("passphrase or key file is empty".to_string()));
+        }
+        *self.secret.borrow_mut() = Some(secret.clone());
+        Ok(secret)
+    }
+}
+
+/// asks for passphrase on terminal
+fn prompt(confirm: bool) -> Result<String> {
+    let not_given = || {
+        Error::Usage(format!(
+            "encrypted configs need passphrase, set {} or use --key-file",
+            PASSPHRASE_VARIABLE
+        ))
+    };
+    if !std::io::stdin().is_terminal() {
+        return Err(not_given());
+    }
+    let passphrase = rpassword::prompt_password("Passphrase: ").map_err(|_| not_given())?;
+    if confirm
+        && rpassword::prompt_password("Repeat passphrase: ").map_err(|_| not_given())? != passphrase
+    {
+        return Err(Error::Usage("passphrases do not match".to_string()));
+    }
+    Ok(passphrase)
+}
+
+/// derives encryption key from secret with argon2id
+fn derive_key(secret: &[u8], salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(|e| Error::Usage(format!("could not derive key: {}", e)))?;
+    Ok(key)
+}
+
+/// encrypts data by key, salt is stored so key can be derived again
+fn seal(data: &[u8], key: &Key, salt: &[u8; SALT_LEN]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(key)
+        .encrypt(Nonce::from_slice(&nonce), data)
+        .map_err(|_| Error::Decode("could not encrypt data".to_string()))?;
+    Ok([MAGIC, salt, &nonce, &ciphertext].concat())
+}
+
+/// decrypts data sealed by seal, key is looked up by stored salt
+fn open(data: &[u8], key: impl FnOnce(&[u8; SALT_LEN]) -> Result<Key>) -> Result<Vec<u8>> {
+    let header = MAGIC.len() + SALT_LEN + NONCE_LEN;
+    if data.len() < header || !data.starts_with(MAGIC) {
+        return Err(Error::Decode("data is not encrypted".to_string()));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&data[MAGIC.len()..MAGIC.len() + SALT_LEN]);
+    let nonce = Nonce::from_slice(&data[MAGIC.len() + SALT_LEN..header]);
+    ChaCha20Poly1305::new(&key(&salt)?)
+        .decrypt(nonce, &data[header..])
+        .map_err(|_| Error::Decode("wrong passphrase or key file (or damaged data)".to_string()))
+}
+
+/// testing that data comes back only with the right key and undamaged
+#[test]
+fn encryption() -> Result<()> {
+    let salt = [7u8; SALT_LEN];
+    let key = derive_key(b"correct horse", &salt)?;
+    let data: &[u8] = b"machine example.com login me password secret\n";
+
+    // round trip
+    let sealed = seal(data, &key, &salt)?;
+    assert!(!sealed.windows(data.len()).any(|window| window == data));
+    assert_ne!(sealed, seal(data, &key, &salt)?);
+    assert_eq!(data.to_vec(), open(&sealed, |_| Ok(key))?);
+
+    // wrong key, damaged data and plain data
+    let wrong = derive_key(b"battery staple", &salt)?;
+    assert!(matches!(
+        open(&sealed, |_| Ok(wrong)),
+        Err(Error::Decode(_))
+    ));
+    let mut damaged = sealed.clone();
+    *damaged.last_mut().expect("sealed data") ^= 1;
+    assert!(matches!(open(&damaged, |_| Ok(key)), Err(Error::Decode(_))));
+    assert!(matches!(open(data, |_| Ok(key)), Err(Error::Decode(_))));
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::collections::HashMap;
+
+use super::{Error, Result};
+
+/// placeholder found in template line
+struct Placeholder<'t> {
+    /// byte range of whole placeholder ({{ name }}) in line
+    start: usize,
+    end: usize,
+    name: &'t str,
+}
+
+/// renders template, every {{ name }} is replaced by value of variable
+/// (unknown variables are reported as NotFound)
+pub fn render(template: &str, variables: &HashMap<String, String>) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(placeholder) = next_placeholder(rest) {
+        let value = variables
+            .get(placeholder.name)
+            .ok_or_else(|| Error::NotFound(format!("template variable {}", placeholder.name)))?;
+        rendered.push_str(&rest[..placeholder.start]);
+        rendered.push_str(value);
+        rest = &rest[placeholder.end..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// maps file rendered from template and then edited by hand back to template,
+/// lines edited by hand are taken as they are, untouched lines keep their placeholders
+/// returns None when an edited line of template contained placeholder
+/// (the edit can not be mapped back without losing it)
+pub fn reverse(
+    template: &str,
+    variables: &HashMap<String, String>,
+    edited: &str,
+) -> Result<Option<String>> {
+    let template_lines: Vec<&str> = template.split_inclusive('\n').collect();
+    let rendered_lines: Vec<String> = template_lines
+        .iter()
+        .map(|line| render(line, variables))
+        .collect::<Result<_>>()?;
+    let rendered_refs: Vec<&str> = rendered_lines.iter().map(String::as_str).collect();
+    let edited_lines: Vec<&str> = edited.split_inclusive('\n').collect();
+
+    let mut reversed = String::with_capacity(edited.len());
+    for op in capture_diff_slices(Algorithm::Myers, &rendered_refs, &edited_lines) {
+        match op {
+            DiffOp::Equal { old_index, len, .. } => {
+                reversed.extend(template_lines[old_index..old_index + len].iter().copied());
+            }
+            DiffOp::Insert {
+                new_index, new_len, ..
+            } => {
+                reversed.extend(edited_lines[new_index..new_index + new_len].iter().copied());
+            }
+            DiffOp::Delete {
+                old_index, old_len, ..
+            }
+            | DiffOp::Replace {
+                old_index, old_len, ..
+            } => {
+                let removed = &template_lines[old_index..old_index + old_len];
+                if removed.iter().any(|line| next_placeholder(line).is_some()) {
+                    return Ok(None);
+                }
+                if let DiffOp::Replace {
+                    new_index, new_len, ..
+                } = op
+                {
+                    reversed.extend(edited_lines[new_index..new_index + new_len].iter().copied());
+                }
+            }
+        }
+    }
+    Ok(Some(reversed))
+}
+
+/// first {{ name }} placeholder in text (name of letters, digits and underscores)
+fn next_placeholder(text: &str) -> Option<Placeholder<'_>> {
+    let mut offset = 0;
+    while let Some(found) = text[offset..].find("{{") {
+        let start = offset + found;
+        if let Some(length) = text[start + 2..].find("}}") {
+            let name = text[start + 2..start + 2 + length].trim();
+            if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Some(Placeholder {
+                    start,
+                    end: start + 2 + length + 2,
+                    name,
+                });
+            }
+        }
+        offset = start + 2;
+    }
+    None
+}
+
+/// testing rendering of placeholders and mapping of edited files back
+#[test]
+fn template_rendering() -> Result<()> {
+    let variables: HashMap<String, String> = [("font_size", "12"), ("email", "me@home")]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let template = "font={{ font_size }}\nuser={{email}}\n{{ not a var }}\nend\n";
+
+    // render
+    assert_eq!(
+        "font=12\nuser=me@home\n{{ not a var }}\nend\n",
+        render(template, &variables)?
+    );
+    assert!(matches!(
+        render("{{ missing }}", &variables),
+        Err(Error::NotFound(_))
+    ));
+
+    // edits of plain lines keep placeholders
+    assert_eq!(
+        Some(
+            "font={{ font_size }}\nuser={{email}}\n{{ not a var }}\nchanged end\nnew\n".to_string()
+        ),
+        reverse(
+            template,
+            &variables,
+            "font=12\nuser=me@home\n{{ not a var }}\nchanged end\nnew\n"
+        )?
+    );
+    // edit of line with placeholder is refused
+    assert_eq!(
+        None,
+        reverse(
+            template,
+            &variables,
+            "font=14\nuser=me@home\n{{ not a var }}\nend\n"
+        )?
+    );
+
+    Ok(())
+}
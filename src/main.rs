@@ -1,149 +1,238 @@
+#![allow(special_module_name)]
+
 extern crate clap;
-use clap::{crate_authors, crate_version, App, Arg};
+use clap::{ArgMatches, ErrorKind, Shell};
 
+mod cli;
 mod lib;
 
-static COMMANDS: &'static [&str] = &["init", "read", "write", "delete", "add", "list", "update"];
-
 fn main() {
-    let matches = App::new("Rusty Configs")
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about("Synchronizes configs across devices with sqlite")
-        .arg(
-            Arg::with_name("command")
-                .help(&format!("Command to run. Options: {}", COMMANDS.join(", ")))
-                .index(1),
-        )
-        .arg(
-            Arg::with_name("entity")
-                .help("Specifies target entity of command")
-                .index(2),
-        )
-        .arg(
-            Arg::with_name("value")
-                .help("New value for updating of config or version attributes")
-                .index(3),
-        )
-        .arg(
-            Arg::with_name("path")
-                .long("path")
-                .short("p")
-                .takes_value(true)
-                .help("Path of target config"),
-        )
-        .arg(
-            Arg::with_name("name")
-                .long("name")
-                .short("n")
-                .takes_value(true)
-                .help("Name of target config (for path /tmp/test it is -n test)"),
-        )
-        .arg(
-            Arg::with_name("id")
-                .long("id")
-                .short("i")
-                .takes_value(true)
-                .help("Id of target config"),
-        )
-        .arg(
-            Arg::with_name("config-version")
-                .short("v")
-                .long("config-version")
-                .takes_value(true)
-                .help("Version of config (name of the system where it is)"),
-        )
-        .arg(
-            Arg::with_name("database")
-                .long("db")
-                .short("d")
-                .takes_value(true)
-                .help("Sqlite database file location)"),
-        )
-        .get_matches();
+    let matches = cli::build_cli()
+        .get_matches_safe()
+        .unwrap_or_else(|e| match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                std::process::exit(lib::Error::Usage(String::new()).exit_code());
+            }
+        });
 
-    let command = matches.value_of("command").unwrap_or("help");
-    let db = matches
-        .value_of("database")
-        .unwrap_or(lib::DEFAULT_DB_LOCATION);
+    let ctx = lib::Context {
+        db: matches
+            .value_of("database")
+            .unwrap_or(lib::DEFAULT_DB_LOCATION)
+            .to_string(),
+        dry_run: matches.is_present("dry-run"),
+        secrets: lib::secret::Secrets::new(matches.value_of("key-file")),
+    };
+    if let Err(e) = run(&ctx, &matches) {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+    if matches.is_present("dry-run") {
+        println!("dry run: nothing was changed");
+    }
+}
 
-    match command {
-        "read" => lib::read_all(db).expect("read failed"),
-        "write" => lib::write_all(db).expect("write failed"),
-        "list" => match matches.value_of("entity") {
-            Some("version") | Some("versions") => match matches.value_of("value") {
-                Some(value) => lib::list_version(db, value).expect("listing of version and its configs failed"),
-                None => lib::list_versions(db).expect("listing of versions failed"),
-            },
-            Some("config") | Some("configs") => lib::list_configs(db).expect("listing of configs failed"),
-            Some(_) | None => println!(
-                "You need to specify what you wanna list as a second argument (version/config)"
+/// runs subcommand selected by arguments
+fn run(ctx: &lib::Context, matches: &ArgMatches) -> lib::Result<()> {
+    match matches.subcommand() {
+        ("init", Some(_)) => lib::init_db(ctx),
+        ("migrate", Some(_)) => lib::migrate(ctx),
+        ("add", Some(args)) => match args.subcommand() {
+            ("config", Some(args)) if args.is_present("recursive") => lib::add_directory(
+                ctx,
+                required(args, "path")?,
+                required(args, "config-version")?,
+                &values(args, "include"),
+                &values(args, "exclude"),
+            ),
+            ("config", Some(args)) => lib::add_config(
+                ctx,
+                required(args, "path")?,
+                required(args, "config-version")?,
+                args.is_present("follow"),
+                args.is_present("template"),
+                args.is_present("encrypt"),
+            ),
+            ("version", Some(args)) => lib::add_version(
+                ctx,
+                required(args, "config-version")?,
+                args.value_of("parent"),
+            ),
+            ("variable", Some(args)) => lib::add_variable(
+                ctx,
+                required(args, "config-version")?,
+                required(args, "value")?,
             ),
+            _ => usage("version / config / variable (you need to specify entity to add)"),
         },
-        "delete" => match matches.value_of("entity") {
-            Some("version") | Some("versions") => match matches.value_of("config-version") {
-                Some(value) => lib::delete_version(db, value).expect("delete version failed"),
-                None => println!("You need to specify version name by -v(--config-version)"),
+        ("list", Some(args)) => match args.subcommand() {
+            ("version", Some(args)) => match args.value_of("name") {
+                Some(name) => lib::list_version(ctx, name, format(args)?),
+                None => lib::list_versions(ctx, format(args)?),
             },
-            Some("config") | Some("configs") => match matches.value_of("id"){
-                Some(id) => lib::delete_by_id(db, id.parse::<u64>().expect("could not parse id"))
-                    .expect("delete by id failed"),
-                None => match matches.value_of("path") {
-                    Some(path) => lib::delete_by_path(db, path).expect("delete by path failed"),
-                    None => match matches.value_of("name") {
-                        Some(name) => lib::delete_by_name(db, name).expect("delete by name failed"),
-                        None => {
-                            println!("You need either -i(--id) or -p(--path) for this command to work")
-                        }
-                    },
-                },
+            ("config", Some(args)) => lib::list_configs(ctx, format(args)?),
+            ("variable", Some(args)) => {
+                lib::list_variables(ctx, required(args, "config-version")?, format(args)?)
             }
-            Some(_) | None => println!(
-                "version / config (you need to specify entity to delete)"
+            _ => usage("version / config / variable (you need to specify entity to list)"),
+        },
+        ("delete", Some(args)) => match args.subcommand() {
+            ("version", Some(args)) => lib::delete_version(ctx, required(args, "config-version")?),
+            ("config", Some(args)) => {
+                if let Some(id) = args.value_of("id") {
+                    lib::delete_by_id(ctx, parse_number(id, "id")?)
+                } else if let Some(path) = args.value_of("path") {
+                    lib::delete_by_path(ctx, path)
+                } else {
+                    lib::delete_by_name(ctx, required(args, "name")?)
+                }
+            }
+            ("variable", Some(args)) => lib::delete_variable(
+                ctx,
+                required(args, "config-version")?,
+                required(args, "name")?,
             ),
+            _ => usage("version / config / variable (you need to specify entity to delete)"),
         },
-        "update" => match matches.value_of("entity") {
-            Some("version") | Some("versions") => match matches.value_of("config-version") {
-                Some(config_version) => match matches.value_of("value") {
-                    Some(new_value) => lib::update_version(db, config_version, new_value)
-                        .expect("update of version failed"),
-                    None => println!( "You need to specify updated value for entity (next positional argument)"),
-                },
-                None => println!("You need to specify version you wanna update"),
-            },
-            Some("config") | Some("configs") => match matches.value_of("path") {
-                Some(path) => match matches.value_of("config-version") {
-                    Some(config_version) => match matches.value_of("value") {
-                        Some(new_value) => lib::update_config(db, path, config_version, new_value).expect("could not update config"),
-                        None => println!( "You need to specify updated value for entity (next positional argument)"),
-                    },
-                    None => println!("You need to specify config version -v(--config-version) to match desired config")
+        ("update", Some(args)) => match args.subcommand() {
+            ("version", Some(args)) => {
+                let name = required(args, "config-version")?;
+                if args.is_present("parent") && args.is_present("no-parent") {
+                    return usage("--parent and --no-parent can not be used together");
                 }
-                None => println!("You need to specify old path to match our config")    
-            },
-            Some(_) | None => println!(
-                "version / config (you need to specify entity to update)"
+                let parent = match args.is_present("parent") || args.is_present("no-parent") {
+                    true => Some(args.value_of("parent")),
+                    false => None,
+                };
+                lib::update_version(ctx, name, args.value_of("value"), parent)
+            }
+            ("config", Some(args)) => lib::update_config(
+                ctx,
+                required(args, "path")?,
+                required(args, "config-version")?,
+                required(args, "value")?,
+            ),
+            ("variable", Some(args)) => lib::update_variable(
+                ctx,
+                required(args, "config-version")?,
+                required(args, "value")?,
             ),
+            _ => usage("version / config / variable (you need to specify entity to update)"),
         },
-        "init" => {
-            lib::init_db(db).expect("fail init db")
-        }
-        "add" => match matches.value_of("entity") {
-            Some("config") | Some("configs") => match matches.value_of("path") {
-                Some(path) => match matches.value_of("config-version") {
-                    Some(config_version) => lib::add_config(db, path, config_version).expect("add config failed"),
-                    None => println!("You need to specify version with -v(--config-version)"),
-                },
-                None => println!("You need to specify path to config file -p(--path)")
-            },
-            Some("version") | Some("versions") => match matches.value_of("config-version") {
-                Some(config_version) => lib::add_version(db, config_version).expect("add version failed"),
-                None => println!("You need to specify -v(--config-version) with a name of new version")
-            },
-            Some(_) | None => println!(
-                "version / config (you need to specify entity to add)"
+        ("bind", Some(args)) => lib::bind(
+            ctx,
+            args.value_of("config-version"),
+            args.value_of("hostname"),
+        ),
+        ("whoami", Some(_)) => lib::whoami(ctx),
+        ("rewrite-paths", Some(_)) => lib::rewrite_paths(ctx),
+        ("read", Some(args)) => lib::read_all(ctx, &selector(args)),
+        ("write", Some(args)) => match (args.value_of("revision"), args.value_of("at")) {
+            (Some(revision), _) => lib::write_revision(
+                ctx,
+                parse_number(revision, "revision")?,
+                !args.is_present("no-owner"),
             ),
+            (None, Some(date)) => {
+                lib::write_at(ctx, &selector(args), date, !args.is_present("no-owner"))
+            }
+            (None, None) => lib::write_all(ctx, &selector(args), !args.is_present("no-owner")),
         },
-        "help" | _ => println!("unknown command!\noptions: {}", COMMANDS.join(", ")),
+        ("undo", Some(_)) => lib::undo(ctx),
+        ("import", Some(args)) => lib::import(
+            ctx,
+            args.value_of("config-version"),
+            required(args, "from")?,
+            args.value_of("target"),
+            args.is_present("stow"),
+            &values(args, "exclude"),
+        ),
+        ("export", Some(args)) => lib::export(
+            ctx,
+            required(args, "config-version")?,
+            required(args, "to")?,
+            args.is_present("decrypt-secrets"),
+        ),
+        ("history", Some(args)) => lib::history(ctx, required(args, "path")?, format(args)?),
+        ("diff", Some(args)) => {
+            // exit code follows diff(1): 0 when identical, 1 when something differs
+            if lib::diff(ctx, &selector(args))? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        ("status", Some(args)) => lib::status(
+            ctx,
+            &selector(args),
+            args.is_present("porcelain"),
+            format(args)?,
+        ),
+        ("sync", Some(args)) => {
+            let conflicts = lib::sync(
+                ctx,
+                &selector(args),
+                args.is_present("markers"),
+                !args.is_present("no-owner"),
+            )?;
+            if conflicts {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        ("completions", Some(args)) => {
+            let shell: Shell = required(args, "shell")?
+                .parse()
+                .map_err(|e: String| lib::Error::Usage(e))?;
+            cli::build_cli().gen_completions_to("rusty-configs", shell, &mut std::io::stdout());
+            Ok(())
+        }
+        _ => usage("unknown command, see --help"),
+    }
+}
+
+/// value of argument clap already validated as required
+fn required<'a>(matches: &'a ArgMatches, name: &str) -> lib::Result<&'a str> {
+    matches
+        .value_of(name)
+        .ok_or_else(|| lib::Error::Usage(format!("missing argument {}", name)))
+}
+
+/// configs selected by -p, -n, -v, -g and -a arguments
+fn selector<'a>(matches: &'a ArgMatches) -> lib::Selector<'a> {
+    lib::Selector {
+        path: matches.value_of("path"),
+        name: matches.value_of("name"),
+        version: matches.value_of("config-version"),
+        glob: matches.value_of("glob"),
+        all_versions: matches.is_present("all-versions"),
     }
 }
+
+/// output format given by --format (tree when not given)
+fn format(matches: &ArgMatches) -> lib::Result<lib::output::Format> {
+    match matches.value_of("format") {
+        Some(format) => format.parse().map_err(lib::Error::Usage),
+        None => Ok(lib::output::Format::default()),
+    }
+}
+
+/// all values of repeatable argument (empty when not given)
+fn values<'a>(matches: &'a ArgMatches, name: &str) -> Vec<&'a str> {
+    matches
+        .values_of(name)
+        .map_or_else(Vec::new, |values| values.collect())
+}
+
+/// usage error with given message
+fn usage(message: &str) -> lib::Result<()> {
+    Err(lib::Error::Usage(message.to_string()))
+}
+
+/// parses numeric argument, named by what in error message
+fn parse_number<T: std::str::FromStr>(value: &str, what: &str) -> lib::Result<T> {
+    value
+        .parse::<T>()
+        .map_err(|_| lib::Error::Usage(format!("could not parse {} {}", what, value)))
+}
@@ -3,10 +3,10 @@
 commands="cargo build ; ./target/debug/rusty-configs init
 cargo build ; ./target/debug/rusty-configs read
 cargo build ; ./target/debug/rusty-configs write
-cargo build ; ./target/debug/rusty-configs delete -p /tmp/test
-cargo build ; ./target/debug/rusty-configs delete -i 1
-cargo build ; ./target/debug/rusty-configs delete -n test
-cargo build ; ./target/debug/rusty-configs add -p /tmp/test4 -v 1
+cargo build ; ./target/debug/rusty-configs delete config -p /tmp/test
+cargo build ; ./target/debug/rusty-configs delete config -i 1
+cargo build ; ./target/debug/rusty-configs delete config -n test
+cargo build ; ./target/debug/rusty-configs add config -p /tmp/test4 -v 1
 cargo build ; ./target/debug/rusty-configs list version
 cargo build ; ./target/debug/rusty-configs list config
 "